@@ -0,0 +1,256 @@
+//! Reading options from a TOML file, see `--config`
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use structopt::{
+    clap::{self, AppSettings, ArgMatches, ErrorKind},
+    StructOpt,
+};
+use toml::Value;
+
+use crate::{Args, R};
+
+/// Parse the command line, filling in anything it leaves out from the
+/// `--config` file if one was provided.
+///
+/// The file's keys are the long option names, with either `-` or `_`, and
+/// its values are turned back into arguments placed ahead of the command
+/// line so they go through the same parsing and validation
+pub fn parse_args() -> R<Args> {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let path = match config_path(&cli[1..]) {
+        Some(path) => path,
+        None => return Ok(Args::from_iter(cli)),
+    };
+    let s = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+    let table: toml::Table = toml::from_str(&s)
+        .map_err(|e| format!("Failed to parse config {}: {}", path.display(), e))?;
+    merge(&cli, table, &path)
+}
+
+/// An argument made from one of the config file's keys
+struct FromConfig {
+    /// The key as it was written in the file
+    key: String,
+    /// The long option name the key is for
+    name: String,
+    arg: OsString,
+}
+
+/// Parse `cli` with the options in `table` filled in, anything the command
+/// line sets itself or that conflicts with something it sets is taken from
+/// the command line alone
+fn merge(cli: &[OsString], table: toml::Table, path: &Path) -> R<Args> {
+    let mut from_config = Vec::new();
+    for (key, value) in table {
+        let name = key.replace('_', "-");
+        if name == "config" {
+            return Err(format!("{} can't set `config`", path.display()).into());
+        }
+        push_option(&mut from_config, &key, &name, &value)?;
+    }
+    let mut matches = loop {
+        let err = match parse_with_config(cli, &from_config) {
+            Ok(matches) => break matches,
+            Err(err) => err,
+        };
+        let args = err.info.clone().unwrap_or_default();
+        match err.kind {
+            ErrorKind::UnknownArgument => {
+                if let Some(unknown) = from_config
+                    .iter()
+                    .find(|c| args.first() == Some(&format!("--{}", c.name)))
+                {
+                    return Err(
+                        format!("unknown key `{}` in {}", unknown.key, path.display()).into(),
+                    );
+                }
+            }
+            // the command line's side of a conflict wins, like any other
+            // option it sets
+            ErrorKind::ArgumentConflict => {
+                let in_config: Vec<&str> = args
+                    .iter()
+                    .map(|arg| long_name(arg))
+                    .filter(|name| from_config.iter().any(|c| c.name == *name))
+                    .collect();
+                match in_config.as_slice() {
+                    [name] => {
+                        let name = name.to_string();
+                        from_config.retain(|c| c.name != name);
+                        continue;
+                    }
+                    [first, second] => {
+                        return Err(format!(
+                            "`{}` and `{}` in {} can't be used together",
+                            first,
+                            second,
+                            path.display()
+                        )
+                        .into())
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        err.exit()
+    };
+    // an option that can only be given once overrides itself so the command
+    // line's copy wins, but options that can be given more than once collect
+    // every copy so the file's are dropped when the command line has its own
+    let overridden: Vec<String> = from_config
+        .iter()
+        .map(|c| &c.name)
+        .filter(|name| {
+            let in_config = from_config.iter().filter(|c| c.name == **name).count();
+            matches.occurrences_of(name.as_str()) > in_config as u64
+        })
+        .cloned()
+        .collect();
+    if !overridden.is_empty() {
+        from_config.retain(|c| !overridden.contains(&c.name));
+        matches = parse_with_config(cli, &from_config).unwrap_or_else(|e| e.exit());
+    }
+    Ok(Args::from_clap(&matches))
+}
+
+/// The long name in how clap shows an argument in its errors, like `verbose`
+/// for `--verbose` or `interval` for `--interval <interval>`
+fn long_name(arg: &str) -> &str {
+    let arg = arg.trim_start_matches('-');
+    arg.split([' ', '=']).next().unwrap_or(arg)
+}
+
+/// Find `--config` without parsing everything else, which might depend on
+/// what's in the file
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+fn parse_with_config<'a>(
+    cli: &[OsString],
+    from_config: &[FromConfig],
+) -> clap::Result<ArgMatches<'a>> {
+    let args = cli[..1]
+        .iter()
+        .chain(from_config.iter().map(|c| &c.arg))
+        .chain(&cli[1..]);
+    Args::clap()
+        .setting(AppSettings::AllArgsOverrideSelf)
+        .get_matches_from_safe(args)
+}
+
+fn push_option(args: &mut Vec<FromConfig>, key: &str, name: &str, value: &Value) -> R<()> {
+    let mut push = |arg: String| {
+        args.push(FromConfig {
+            key: key.to_string(),
+            name: name.to_string(),
+            arg: arg.into(),
+        })
+    };
+    match value {
+        Value::Boolean(true) => push(format!("--{}", name)),
+        Value::Boolean(false) => {}
+        Value::Array(values) => {
+            for value in values {
+                push(format!("--{}={}", name, scalar(key, value)?));
+            }
+        }
+        value => push(format!("--{}={}", name, scalar(key, value)?)),
+    }
+    Ok(())
+}
+
+fn scalar(key: &str, value: &Value) -> R<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        _ => Err(format!("unsupported value for `{}` in config: {}", key, value).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn parse(config: &str, cli: &[&str]) -> R<Args> {
+        let cli: Vec<OsString> = std::iter::once("vaccine_spotter")
+            .chain(cli.iter().copied())
+            .map(OsString::from)
+            .collect();
+        merge(
+            &cli,
+            toml::from_str(config).unwrap(),
+            Path::new("test.toml"),
+        )
+    }
+
+    #[test]
+    fn command_line_overrides_a_scalar() {
+        let config = "state = \"NJ\"\ninterval = \"120\"";
+        let args = parse(config, &[]).unwrap();
+        assert_eq!(args.interval.for_state("NJ"), Duration::from_secs(120));
+        let args = parse(config, &["--interval", "30"]).unwrap();
+        assert_eq!(args.interval.for_state("NJ"), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn command_line_replaces_every_value_of_a_list() {
+        let config = "state = [\"NJ\", \"NY\"]";
+        assert_eq!(parse(config, &[]).unwrap().state, ["NJ", "NY"]);
+        assert_eq!(parse(config, &["--state", "PA"]).unwrap().state, ["PA"]);
+    }
+
+    #[test]
+    fn booleans_come_from_either_side() {
+        let args = parse("state = \"NJ\"\nshow_all = true", &[]).unwrap();
+        assert!(args.show_all);
+        let args = parse("state = \"NJ\"\nshow_all = false", &["--show-all"]).unwrap();
+        assert!(args.show_all);
+        let args = parse("state = \"NJ\"\nshow-all = true", &["--show-all"]).unwrap();
+        assert!(args.show_all);
+    }
+
+    #[test]
+    fn command_line_wins_a_conflict() {
+        let args = parse("state = \"NJ\"\nquiet = true", &["-v"]).unwrap();
+        assert_eq!(args.verbose, 1);
+        assert!(!args.quiet);
+        let args = parse("state = \"NJ\"\nonly_weekends = true", &["--only-weekdays"]).unwrap();
+        assert!(args.only_weekdays);
+        assert!(!args.only_weekends);
+    }
+
+    #[test]
+    fn conflict_within_the_file_is_an_error() {
+        let err = parse("only_weekends = true\nonly_weekdays = true", &[]).unwrap_err();
+        assert!(
+            err.to_string().contains("can't be used together"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unknown_key_is_an_error() {
+        let err = parse("state = \"NJ\"\nbogus_key = 1", &[]).unwrap_err();
+        assert_eq!(err.to_string(), "unknown key `bogus_key` in test.toml");
+    }
+}
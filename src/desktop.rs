@@ -0,0 +1,38 @@
+//! Desktop notifications, enabled with the `desktop-notifications` feature
+
+use notify_rust::Notification;
+
+use crate::{string_or_question, Properties};
+
+/// Show a notification for each location, a missing notification daemon
+/// is only worth a warning since the other outputs still go out
+pub fn notify_locations(locations: &[&Properties]) {
+    for props in locations {
+        let summary = format!(
+            "{}-{}",
+            string_or_question(&props.provider),
+            string_or_question(&props.name)
+        );
+        let mut body = string_or_question(&props.address).to_string();
+        if let Some(away) = props.miles_away_label() {
+            body.push_str(&format!("\n{}", away));
+        }
+        let next = props
+            .appointments
+            .iter()
+            .flatten()
+            .map(|appt| appt.time)
+            .min();
+        if let Some(next) = next {
+            let (time, zone) = props.local_time(next);
+            body.push_str(&format!(
+                "\nnext: {} {}",
+                time.format("%m/%d/%Y %I:%M%P"),
+                zone
+            ));
+        }
+        if let Err(e) = Notification::new().summary(&summary).body(&body).show() {
+            log::warn!("Failed to show a desktop notification: {}", e);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! A CSV record of every new appointment that was reported, see `--history-csv`
+
+use std::{collections::HashMap, fs::OpenOptions, path::Path};
+
+use chrono::Local;
+
+use crate::{Appointment, Properties, R};
+
+const HEADER: [&str; 7] = [
+    "polled_at",
+    "location_id",
+    "provider",
+    "name",
+    "city",
+    "zip",
+    "appointment",
+];
+
+/// Append a row for each of the locations' appointments that weren't in
+/// `current_info`, writing the header first if the file is new
+pub fn record(
+    path: &Path,
+    locations: &[&Properties],
+    current_info: &HashMap<u64, Vec<Appointment>>,
+) -> R<()> {
+    let is_new = std::fs::metadata(path)
+        .map(|m| m.len() == 0)
+        .unwrap_or(true);
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = csv::Writer::from_writer(file);
+    if is_new {
+        writer.write_record(HEADER)?;
+    }
+    let polled_at = Local::now().to_rfc3339();
+    for props in locations {
+        let old = current_info.get(&props.id);
+        for appt in props.appointments.iter().flatten() {
+            if old.is_some_and(|old| old.contains(appt)) {
+                continue;
+            }
+            writer.write_record([
+                polled_at.as_str(),
+                &props.id.to_string(),
+                props.provider.as_deref().unwrap_or_default(),
+                props.name.as_deref().unwrap_or_default(),
+                props.city.as_deref().unwrap_or_default(),
+                props.postal_code.as_deref().unwrap_or_default(),
+                &appt.time.to_rfc3339(),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
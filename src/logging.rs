@@ -0,0 +1,195 @@
+//! Setting up the logger from `--verbose`, `--quiet`, `--log-format` and `--log-file`
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{Args, LogFormat, R};
+
+/// The latest error logged while the dashboard has the terminal
+static LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set up the logger, `RUST_LOG` takes precedence over the level from
+/// `--verbose` and `--quiet` when it's set.
+///
+/// The json logger forwards the `log` macros through `tracing` so they pick
+/// up the fields of the current poll's span. While the `--tui` dashboard has
+/// the terminal nothing is written to stderr, logs only go to `--log-file`
+/// and the latest error is kept for the dashboard's status line
+pub fn init(args: &Args) -> R<()> {
+    let level = level(args.verbose, args.quiet);
+    let file = args.log_file.as_deref().map(open).transpose()?;
+    #[cfg(feature = "tui")]
+    let dashboard = args.tui;
+    #[cfg(not(feature = "tui"))]
+    let dashboard = false;
+    match args.log_format {
+        LogFormat::Pretty => {
+            let mut builder = pretty_env_logger::formatted_builder();
+            builder.filter_level(level);
+            if let Ok(filters) = std::env::var("RUST_LOG") {
+                builder.parse_filters(&filters);
+            }
+            let logger = builder.build();
+            let max_level = logger.filter();
+            let logger: Box<dyn Log> = if dashboard {
+                Box::new(Captured {
+                    inner: Box::new(logger),
+                })
+            } else {
+                Box::new(logger)
+            };
+            let logger: Box<dyn Log> = match file {
+                Some(file) => Box::new(Tee {
+                    inner: logger,
+                    file: Mutex::new(file),
+                }),
+                None => logger,
+            };
+            log::set_boxed_logger(logger)?;
+            log::set_max_level(max_level);
+        }
+        LogFormat::Json => {
+            use tracing_subscriber::{
+                fmt::writer::{BoxMakeWriter, MakeWriterExt},
+                prelude::*,
+                EnvFilter,
+            };
+            let filter = EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(level.as_str().to_lowercase()));
+            let writer = match (file, dashboard) {
+                (Some(file), true) => BoxMakeWriter::new(Arc::new(file)),
+                (None, true) => BoxMakeWriter::new(std::io::sink),
+                (Some(file), false) => BoxMakeWriter::new(std::io::stderr.and(Arc::new(file))),
+                (None, false) => BoxMakeWriter::new(std::io::stderr),
+            };
+            tracing_subscriber::registry()
+                .with(filter)
+                .with(tracing_subscriber::fmt::layer().json().with_writer(writer))
+                .with(dashboard.then_some(CapturedLayer))
+                .init();
+        }
+    }
+    Ok(())
+}
+
+/// Warnings and errors by default, each `-v` adds a level and `-q` leaves
+/// only errors
+fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Take the latest error logged since the last call
+#[cfg(feature = "tui")]
+pub fn take_last_error() -> Option<String> {
+    LAST_ERROR.lock().ok()?.take()
+}
+
+fn set_last_error(error: String) {
+    if let Ok(mut last) = LAST_ERROR.lock() {
+        *last = Some(error);
+    }
+}
+
+fn open(path: &Path) -> R<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open log file {}: {}", path.display(), e).into())
+}
+
+/// Log to `inner` as usual and also append plain lines to `file`, without the
+/// colors the terminal gets
+struct Tee {
+    inner: Box<dyn Log>,
+    file: Mutex<File>,
+}
+
+impl Log for Tee {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        if let Ok(mut file) = self.file.lock() {
+            // there's nowhere left to report a failure to write the log
+            let _ = writeln!(
+                file,
+                "{} {:<5} {} > {}",
+                chrono::Local::now().to_rfc3339(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Stands in for the terminal logger while the dashboard is shown, going
+/// by `inner`'s filters but only keeping the latest error
+struct Captured {
+    inner: Box<dyn Log>,
+}
+
+impl Log for Captured {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) && record.level() == Level::Error {
+            set_last_error(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// `Captured` for the json logger
+struct CapturedLayer;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CapturedLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        if *event.metadata().level() == tracing::Level::ERROR {
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            set_last_error(message.0);
+        }
+    }
+}
+
+/// Pulls the message out of a `tracing` event
+struct Message(String);
+
+impl tracing::field::Visit for Message {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
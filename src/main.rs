@@ -1,141 +1,2555 @@
-use std::{collections::HashMap, path::PathBuf};
-use chrono::{Date, DateTime, Local};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use chrono_tz::Tz;
+use futures::{FutureExt, StreamExt};
+use owo_colors::{OwoColorize, Style};
 use structopt::StructOpt;
+use tracing::Instrument;
 
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Serialize};
+
+use vaccinespotter::{fetch_state, Appointment, Coordinate, Feature, FetchError, Properties};
+
+mod config;
+#[cfg(feature = "desktop-notifications")]
+mod desktop;
+mod history;
+mod logging;
+mod metrics;
+mod output;
+#[cfg(feature = "qr")]
+mod qr;
+#[cfg(feature = "sms-notifications")]
+mod sms;
+mod states;
+#[cfg(feature = "telegram-notifications")]
+mod telegram;
+#[cfg(feature = "tui")]
+mod tui;
+mod vaccinespotter;
 
 type R<T> = Result<T, Box<dyn std::error::Error>>;
 
 #[derive(StructOpt, Debug)]
 struct Args {
+    #[structopt(long)]
+    /// A TOML file of options keyed by their long names, e.g. `state = "NJ"`,
+    /// anything provided on the command line takes precedence
+    config: Option<PathBuf>,
     #[structopt(short, long)]
     /// The path to a json file containing an array of strings representing
-    /// the target zip codes. If not provided all zipcodes will be considered
+    /// the target zip codes, or a text file with one per line and `#`
+    /// comments. Entries shorter than 5 digits like `088` match every zip
+    /// code starting with them. If not provided all zipcodes will be
+    /// considered
     zips_path: Option<PathBuf>,
-    #[structopt(short, long)]
-    /// the 2 digit state code to use to get current appointments
-    state: String,
+    #[structopt(long, parse(try_from_str = zip_region))]
+    /// Also consider every zipcode sharing the first 3 digits with this one,
+    /// can be provided more than once
+    zip_neighbors: Vec<String>,
+    #[structopt(
+        long,
+        requires = "radius-miles",
+        allow_hyphen_values = true,
+        number_of_values = 1
+    )]
+    /// Also consider every location within `--radius-miles` of this point,
+    /// given as `LAT,LON`. Can be provided more than once to watch around
+    /// several places
+    near: Vec<Coordinate>,
+    #[structopt(long, requires = "near", number_of_values = 1)]
+    /// How far from `--near` a location can be, either once for every
+    /// `--near` or once for each in the same order
+    radius_miles: Vec<f64>,
+    #[structopt(long, requires = "near")]
+    /// Say how far each location is from the closest `--near` in reports
+    /// and alerts
+    include_distance_in_alerts: bool,
+    #[structopt(short, long, use_delimiter = true)]
+    /// the 2 digit state codes to use to get current appointments, comma
+    /// separated or provided more than once
+    state: Vec<String>,
     #[structopt(short, long)]
     /// The email address to send alerts from
     from_email: Option<String>,
     #[structopt(short, long)]
     /// The email address to send alerts to
     to_email: Option<String>,
+    #[structopt(long, requires = "from-email")]
+    /// The address to use as the SMTP envelope sender when it needs to differ
+    /// from the From header, e.g. the authenticated user for strict relays
+    envelope_from: Option<String>,
+    #[cfg(feature = "email-notifications")]
+    #[structopt(long)]
+    /// The SMTP relay to send alerts through, e.g. `smtp.gmail.com`. Without it
+    /// alerts go to an unencrypted server on port 25 of this machine
+    smtp_host: Option<String>,
+    #[cfg(feature = "email-notifications")]
+    #[structopt(long, requires = "smtp-host")]
+    /// The port of `--smtp-host`, 465 (the default) uses TLS from the start
+    /// and any other port upgrades with STARTTLS
+    smtp_port: Option<u16>,
+    #[cfg(feature = "email-notifications")]
+    #[structopt(long, requires_all = &["smtp-host", "smtp-pass"])]
+    /// The username to log in to `--smtp-host` with
+    smtp_user: Option<String>,
+    #[cfg(feature = "email-notifications")]
+    #[structopt(long, requires = "smtp-user")]
+    /// The password for `--smtp-user`
+    smtp_pass: Option<Secret>,
+    #[structopt(long)]
+    /// The timezone to read appointment times that don't include an offset in
+    /// at locations without a timezone of their own, e.g. `America/New_York`.
+    /// Defaults to the timezone of the state
+    assume_timezone: Option<Tz>,
+    #[structopt(long, visible_alias = "interval-secs", default_value = "60")]
+    /// The number of seconds to wait between polls. Individual states can be
+    /// overridden with a comma separated list like `60,TX=30,CA=120`
+    interval: Intervals,
+    #[structopt(
+        long,
+        default_value = "https://www.vaccinespotter.org/api/v0",
+        visible_alias = "base-url"
+    )]
+    /// The base url of the vaccinespotter api, or a mirror of it. A `file://`
+    /// url reads saved responses from disk instead, e.g. for testing
+    api_base: String,
+    #[structopt(long, default_value = "states/{state}.json")]
+    /// The path under `--api-base` for a state's appointments, `{state}` is
+    /// replaced with the 2 digit state code
+    state_path_template: String,
+    #[structopt(long, default_value = "2")]
+    /// How many times to fetch the state again within a poll when the
+    /// response can't be parsed
+    parse_retries: u32,
+    #[structopt(long, default_value = "4")]
+    /// The most states to fetch at once
+    max_concurrency: usize,
+    #[structopt(long)]
+    /// Log a summary of availability over the last N polls every N polls,
+    /// shown with `-v` or more
+    report_interval_summary: Option<u64>,
+    #[structopt(long)]
+    /// A url to GET after every successful poll, e.g. a healthchecks.io check
+    healthcheck_url: Option<String>,
+    #[structopt(long)]
+    /// Post reports to this Slack incoming webhook instead of printing or
+    /// emailing them. Discord webhooks work with `/slack` on the end of the url
+    webhook_url: Option<String>,
+    #[structopt(long)]
+    /// Add a made up location to the first poll's report to test that alerts
+    /// make it all the way through
+    simulate: bool,
+    #[structopt(long)]
+    /// Exit with an error if any state fails in the first poll instead of
+    /// retrying it a few times first, useful for catching a bad configuration
+    /// under a supervisor
+    fail_fast: bool,
+    #[structopt(long, visible_alias = "no-loop")]
+    /// Poll once, report what's new since the last run's `--state-file` (or
+    /// every available appointment without one) and exit, for running from
+    /// cron or a systemd timer
+    once: bool,
+    #[structopt(long)]
+    /// Print reports instead of sending them anywhere or pinging
+    /// `--healthcheck-url`, without saving anything that would change what a
+    /// real run reports, to try out options
+    dry_run: bool,
+    #[structopt(long)]
+    /// Also send a notice when a location that had appointments last poll
+    /// has none left
+    notify_cleared: bool,
+    #[structopt(long)]
+    /// Skip protocol negotiation and always talk to the api over HTTP/2
+    http2_prior_knowledge: bool,
+    #[structopt(short, long)]
+    /// Append reports to this file instead of printing them to stdout, strftime
+    /// placeholders like `reports/%Y%m%d/%H%M%S.txt` are filled in for each report
+    output: Option<PathBuf>,
+    #[structopt(long)]
+    /// Also append a row to this CSV file for every new appointment reported,
+    /// however the report itself is sent
+    history_csv: Option<PathBuf>,
+    #[structopt(long)]
+    /// Serve Prometheus metrics from `/metrics` on this port
+    metrics_port: Option<u16>,
+    #[structopt(long, requires = "output")]
+    /// Roll the output file over, either `daily` or once it reaches a size like `10MB`
+    output_rotate: Option<output::Rotate>,
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    /// Print and write reports for people (`text`) or as a JSON array of
+    /// locations on a single line (`json`) for tools like `jq`, see `--json-pretty`
+    format: ReportFormat,
+    #[structopt(long)]
+    /// Indent `--format json` over several lines to read it yourself
+    json_pretty: bool,
+    #[structopt(long)]
+    /// With `--format json`, describe each location once in a `locations` map
+    /// and only list its id and appointments after that, see the README
+    locations_cache: bool,
+    #[structopt(long)]
+    /// Only alert when a location starts offering appointments for all doses
+    /// instead of whenever it has new appointments
+    watch_all_doses: bool,
+    #[structopt(long)]
+    /// Keep the appointments seen in the last poll in this file so a restart
+    /// only reports what's new since, unless `--show-all` is set
+    state_file: Option<PathBuf>,
+    #[structopt(long)]
+    /// Don't alert on a location again until this many minutes after the
+    /// last alert for it, even if it has new appointments
+    cooldown_mins: Option<u64>,
+    #[structopt(long, use_delimiter = true)]
+    /// Send at most one report or notice through a channel every so many
+    /// minutes, like `sms=15`, dropping any in between. Channels are `webhook`,
+    /// `email`, `output`, `stdout`, and `sms`, `telegram` or `desktop` when built with them
+    notify_cooldown_per_channel: Vec<ChannelCooldown>,
+    #[structopt(long, default_value = "1")]
+    /// Only report a location with at least this many new appointments,
+    /// or this many appointments in total with `--show-all`
+    min_appointments: usize,
+    #[structopt(long)]
+    /// Only alert on a location the first time it is ever reported
+    first_availability_only: bool,
+    #[structopt(long, requires = "first-availability-only")]
+    /// Remember the locations `--first-availability-only` has reported in
+    /// this file so they stay quiet across restarts
+    first_availability_file: Option<PathBuf>,
+    #[structopt(long, conflicts_with = "watch-all-doses")]
+    /// Report every matching location with appointments each poll,
+    /// not just the ones with new appointments
+    show_all: bool,
+    #[structopt(long)]
+    /// Only consider the location with this provider's store number, can be
+    /// provided more than once
+    store: Vec<String>,
+    #[structopt(long)]
+    /// Only consider locations from providers with this in their name, like
+    /// `cvs`, can be provided more than once
+    provider: Vec<String>,
+    #[structopt(long)]
+    /// Skip locations from providers with this in their name, can be provided
+    /// more than once
+    exclude_provider: Vec<String>,
+    #[structopt(long, default_value = "all", possible_values = &["all", "first", "second"])]
+    /// Only consider locations offering `first` dose appointments (which book
+    /// the whole series) or `second` dose only appointments
+    dose: Dose,
+    #[structopt(long)]
+    /// Only consider appointments for this vaccine, e.g. `pfizer`. Appointments
+    /// that don't say which vaccine they are for are skipped
+    vaccine: Option<String>,
+    #[structopt(long, conflicts_with = "only-weekdays")]
+    /// Only consider appointments on Saturday or Sunday
+    only_weekends: bool,
+    #[structopt(long)]
+    /// Only consider appointments Monday through Friday
+    only_weekdays: bool,
+    #[structopt(
+        long,
+        default_value = "combined",
+        possible_values = &["combined", "per-location"]
+    )]
+    /// Send the new locations from each poll in one report (`combined`) or in
+    /// a report each (`per-location`)
+    alert_granularity: Granularity,
+    #[structopt(long, default_value = "asc", possible_values = &["asc", "desc"])]
+    /// List appointments soonest first (`asc`) or latest first (`desc`)
+    appointments_sort: SortOrder,
+    #[structopt(long, default_value = "soonest", possible_values = &["soonest", "distance", "name"])]
+    /// List locations by their earliest new appointment (`soonest`), how far
+    /// they are from `--near` (`distance`) or by provider and name (`name`)
+    sort: LocationSort,
+    #[structopt(long, default_value = "id", possible_values = &["id", "url"])]
+    /// Treat locations as the same when they share an `id`, or also when they
+    /// share a booking `url` so a pharmacy listed in two states is only
+    /// reported once per `--cooldown-mins`, or without one until every state
+    /// has been polled again. With `url`, locations behind one generic
+    /// provider url hide each other, keeping the first in `--sort` order
+    dedupe_by: Dedupe,
+    #[structopt(long)]
+    /// Ignore appointments more than this many days from now, far out
+    /// appointments are often placeholders that get cancelled
+    max_days_out: Option<u16>,
+    #[structopt(long, parse(try_from_str = time_of_day))]
+    /// Ignore appointments before this time of day, as `HH:MM`
+    after: Option<NaiveTime>,
+    #[structopt(long, parse(try_from_str = time_of_day))]
+    /// Ignore appointments at or after this time of day, as `HH:MM`. If it's
+    /// earlier than `--after` the window runs overnight
+    before: Option<NaiveTime>,
+    #[structopt(long, use_delimiter = true)]
+    /// A comma separated list of providers to list first in reports, in the order given
+    provider_priority: Vec<String>,
+    #[structopt(long, default_value = "pretty", possible_values = &["pretty", "json"])]
+    /// Write log messages for people (`pretty`) or as one JSON object per line
+    /// for a log aggregator (`json`)
+    log_format: LogFormat,
+    #[structopt(short, long, parse(from_occurrences))]
+    /// Log more, `-v` for what each poll found and `-vv` or more for debugging.
+    /// `RUST_LOG` overrides this when it's set
+    verbose: u8,
+    #[structopt(short, long, conflicts_with = "verbose")]
+    /// Only log errors
+    quiet: bool,
+    #[structopt(long)]
+    /// Also append log messages to this file
+    log_file: Option<PathBuf>,
+    #[structopt(long)]
+    /// Don't color printed reports, color is also left off when stdout isn't
+    /// a terminal or `NO_COLOR` is set
+    no_color: bool,
+    #[cfg(feature = "desktop-notifications")]
+    #[structopt(long)]
+    /// Also show a desktop notification for each reported location
+    desktop: bool,
+    #[cfg(feature = "sms-notifications")]
+    #[structopt(long, requires_all = &["twilio-token", "twilio-from", "sms-to"])]
+    /// The Twilio account SID to send text alerts with
+    twilio_sid: Option<String>,
+    #[cfg(feature = "sms-notifications")]
+    #[structopt(long, requires = "twilio-sid")]
+    /// The auth token for `--twilio-sid`
+    twilio_token: Option<Secret>,
+    #[cfg(feature = "sms-notifications")]
+    #[structopt(long, requires = "twilio-sid")]
+    /// The Twilio phone number to text alerts from, e.g. `+15555550100`
+    twilio_from: Option<String>,
+    #[cfg(feature = "sms-notifications")]
+    #[structopt(long, requires = "twilio-sid")]
+    /// Text a short summary of reports to this phone number instead of
+    /// posting, emailing or printing them
+    sms_to: Option<String>,
+    #[cfg(feature = "telegram-notifications")]
+    #[structopt(long, requires = "telegram-chat-id")]
+    /// The token of the Telegram bot to send alerts from
+    telegram_token: Option<Secret>,
+    #[cfg(feature = "telegram-notifications")]
+    #[structopt(long, requires = "telegram-token")]
+    /// Send reports to this Telegram chat instead of posting, emailing or
+    /// printing them
+    telegram_chat_id: Option<String>,
+    #[cfg(feature = "qr")]
+    #[structopt(long)]
+    /// Write a QR code of each reported location's booking url to this
+    /// directory and reference it in the report
+    qr: Option<PathBuf>,
+    #[cfg(feature = "tui")]
+    #[structopt(long)]
+    /// Show a live dashboard of the matching locations instead of printing reports
+    tui: bool,
+}
+
+impl Args {
+    /// The url to fetch the current appointments for `state` from
+    fn state_url(&self, state: &str) -> String {
+        format!(
+            "{}/{}",
+            self.api_base.trim_end_matches('/'),
+            self.state_path_template
+                .trim_start_matches('/')
+                .replace("{state}", &state.to_uppercase())
+        )
+    }
+
+    /// Where to write the latest appointments, a `--dry-run` leaves them
+    /// alone so the next real run still reports what it printed
+    fn state_file_to_save(&self) -> Option<&Path> {
+        if self.dry_run {
+            return None;
+        }
+        self.state_file.as_deref()
+    }
+
+    /// If reports that aren't emailed or written to a file should go to stdout
+    fn prints_reports(&self) -> bool {
+        #[cfg(feature = "tui")]
+        if self.tui {
+            return false;
+        }
+        true
+    }
+
+    /// The channel `send_report` and `send_cleared` use, checked in the same order
+    fn report_channel(&self) -> Option<Channel> {
+        #[cfg(feature = "sms-notifications")]
+        if self.twilio_sid.is_some() {
+            return Some(Channel::Sms);
+        }
+        #[cfg(feature = "telegram-notifications")]
+        if self.telegram_token.is_some() {
+            return Some(Channel::Telegram);
+        }
+        if self.webhook_url.is_some() {
+            Some(Channel::Webhook)
+        } else if self.from_email.is_some() && self.to_email.is_some() {
+            Some(Channel::Email)
+        } else if self.output.is_some() {
+            Some(Channel::Output)
+        } else if self.prints_reports() {
+            Some(Channel::Stdout)
+        } else {
+            None
+        }
+    }
+}
+
+/// Where a report goes, see `--notify-cooldown-per-channel`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Channel {
+    #[cfg(feature = "sms-notifications")]
+    Sms,
+    #[cfg(feature = "telegram-notifications")]
+    Telegram,
+    #[cfg(feature = "desktop-notifications")]
+    Desktop,
+    Webhook,
+    Email,
+    Output,
+    Stdout,
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            #[cfg(feature = "sms-notifications")]
+            "sms" => Ok(Self::Sms),
+            #[cfg(feature = "telegram-notifications")]
+            "telegram" => Ok(Self::Telegram),
+            #[cfg(feature = "desktop-notifications")]
+            "desktop" => Ok(Self::Desktop),
+            "webhook" => Ok(Self::Webhook),
+            "email" => Ok(Self::Email),
+            "output" => Ok(Self::Output),
+            "stdout" => Ok(Self::Stdout),
+            _ => Err(format!(
+                "expected a channel like `email` or `stdout`, found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            #[cfg(feature = "sms-notifications")]
+            Self::Sms => "sms",
+            #[cfg(feature = "telegram-notifications")]
+            Self::Telegram => "telegram",
+            #[cfg(feature = "desktop-notifications")]
+            Self::Desktop => "desktop",
+            Self::Webhook => "webhook",
+            Self::Email => "email",
+            Self::Output => "output",
+            Self::Stdout => "stdout",
+        })
+    }
+}
+
+/// A `CHANNEL=MINS` of `--notify-cooldown-per-channel`
+#[derive(Debug, Clone, Copy)]
+struct ChannelCooldown {
+    channel: Channel,
+    cooldown: Duration,
+}
+
+impl FromStr for ChannelCooldown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (channel, mins) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected `CHANNEL=MINS` like `sms=15`, found {:?}", s))?;
+        let mins: u64 = mins
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid cooldown {:?}: {}", mins, e))?;
+        Ok(Self {
+            channel: channel.trim().parse()?,
+            cooldown: Duration::from_secs(mins.saturating_mul(60)),
+        })
+    }
+}
+
+/// If `channel` sent something more recently than its
+/// `--notify-cooldown-per-channel` allows
+fn throttled(channel: Channel, last_sent: &HashMap<Channel, Instant>, args: &Args) -> bool {
+    let cooldown = args
+        .notify_cooldown_per_channel
+        .iter()
+        .rev()
+        .find(|c| c.channel == channel)
+        .map(|c| c.cooldown);
+    match (cooldown, last_sent.get(&channel)) {
+        (Some(cooldown), Some(sent)) => sent.elapsed() < cooldown,
+        _ => false,
+    }
+}
+
+/// A password or token, shown as `***` when debug printed so it stays out
+/// of the logs
+#[cfg_attr(
+    not(any(
+        feature = "email-notifications",
+        feature = "sms-notifications",
+        feature = "telegram-notifications"
+    )),
+    allow(dead_code)
+)]
+struct Secret(String);
+
+#[cfg_attr(
+    not(any(
+        feature = "email-notifications",
+        feature = "sms-notifications",
+        feature = "telegram-notifications"
+    )),
+    allow(dead_code)
+)]
+impl Secret {
+    fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "***")
+    }
+}
+
+/// The states being polled, for messages
+fn states_label(states: &[String]) -> String {
+    states.join(",").to_uppercase()
+}
+
+/// The polling cadence, a global default with optional per-state overrides
+#[derive(Debug, Clone)]
+struct Intervals {
+    default: Duration,
+    per_state: HashMap<String, Duration>,
+}
+
+impl Intervals {
+    fn for_state(&self, state: &str) -> Duration {
+        self.per_state
+            .get(&state.to_uppercase())
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl FromStr for Intervals {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a 0 would also back off to 0, hitting a failing api in a tight loop
+        let parse_secs = |v: &str| match v.trim().parse::<u64>() {
+            Ok(0) => Err(format!(
+                "invalid interval {:?}: must be at least 1 second",
+                v
+            )),
+            Ok(secs) => Ok(Duration::from_secs(secs)),
+            Err(e) => Err(format!("invalid interval {:?}: {}", v, e)),
+        };
+        let mut ret = Self {
+            default: Duration::from_secs(60),
+            per_state: HashMap::new(),
+        };
+        for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+            if let Some((state, secs)) = part.split_once('=') {
+                let state = state.trim().to_uppercase();
+                if !states::is_known(&state) {
+                    return Err(format!(
+                        "unknown state code {:?} in interval {:?}",
+                        state, part
+                    ));
+                }
+                ret.per_state.insert(state, parse_secs(secs)?);
+            } else {
+                ret.default = parse_secs(part)?;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// When each state is next due to be polled, so each can be polled on its
+/// own `--interval` and back off on its own failures
+struct Schedule {
+    next: Vec<Due>,
+}
+
+struct Due {
+    state: String,
+    at: Instant,
+    /// How many polls of this state have failed in a row
+    failures: u32,
+}
+
+/// The longest any state is put off, far enough out to never be reached but
+/// small enough that adding it to now can't overflow
+const MAX_WAIT: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+impl Schedule {
+    /// Every state starts out due
+    fn new(states: &[String]) -> Self {
+        let now = Instant::now();
+        Self {
+            next: states
+                .iter()
+                .map(|state| Due {
+                    state: state.clone(),
+                    at: now,
+                    failures: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// The states due to be polled now, in the order they were given
+    fn due(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.next
+            .iter()
+            .filter(|due| due.at <= now)
+            .map(|due| due.state.clone())
+            .collect()
+    }
+
+    /// Poll each of `states` again once `wait` for it has passed
+    fn after(&mut self, states: &[String], wait: impl Fn(&str) -> Duration) {
+        let now = Instant::now();
+        for due in &mut self.next {
+            if states.contains(&due.state) {
+                due.at = now + wait(&due.state).min(MAX_WAIT);
+            }
+        }
+    }
+
+    /// `states` were fetched, poll each again after its `--interval`
+    fn polled(&mut self, states: &[String], interval: &Intervals) {
+        for due in &mut self.next {
+            if states.contains(&due.state) {
+                due.failures = 0;
+            }
+        }
+        self.after(states, |state| interval.for_state(state));
+    }
+
+    /// `state` failed to fetch, poll it again after `retry_after` if the api
+    /// asked for that, or after backing off from its `--interval`, returning
+    /// the wait
+    fn failed(
+        &mut self,
+        state: &str,
+        retry_after: Option<Duration>,
+        interval: &Intervals,
+    ) -> Duration {
+        let due = match self.next.iter_mut().find(|due| due.state == state) {
+            Some(due) => due,
+            None => return Duration::ZERO,
+        };
+        due.failures += 1;
+        let wait = retry_after
+            .unwrap_or_else(|| backoff(interval.for_state(state), due.failures))
+            .min(MAX_WAIT);
+        due.at = Instant::now() + wait;
+        wait
+    }
+
+    /// Make every state due right away
+    #[cfg(feature = "tui")]
+    fn all_due(&mut self) {
+        let now = Instant::now();
+        for due in &mut self.next {
+            due.at = now;
+        }
+    }
+
+    /// How long until the next state is due
+    fn wait(&self) -> Duration {
+        let now = Instant::now();
+        self.next
+            .iter()
+            .map(|due| due.at.saturating_duration_since(now))
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[tokio::main]
+async fn main() -> R<()> {
+    let args = config::parse_args()?;
+    logging::init(&args)?;
+    if let Some(path) = &args.config {
+        log::info!("read options from {}", path.display());
+    }
+    log::debug!("starting with args: {:?}", args);
+    if args.state.is_empty() {
+        return Err("--state is required, on the command line or in --config".into());
+    }
+    let unknown: Vec<String> = args
+        .state
+        .iter()
+        .filter(|state| !states::is_known(state))
+        .map(|state| state.to_uppercase())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "unknown state code {}, expected a 2 digit postal code like NJ",
+            unknown.join(", ")
+        )
+        .into());
+    }
+    for state in args.interval.per_state.keys() {
+        if !args.state.iter().any(|s| s.eq_ignore_ascii_case(state)) {
+            log::warn!("--interval sets {}, which isn't in --state", state);
+        }
+    }
+    if !args.state_path_template.contains("{state}") {
+        return Err(format!(
+            "--state-path-template must contain {{state}}, found {:?}",
+            args.state_path_template
+        )
+        .into());
+    }
+    if args.locations_cache && args.format != ReportFormat::Json {
+        return Err("--locations-cache requires --format json".into());
+    }
+    if args.max_concurrency == 0 {
+        return Err("--max-concurrency must be at least 1".into());
+    }
+    if args.sort == LocationSort::Distance && args.near.is_empty() {
+        return Err("--sort distance requires --near".into());
+    }
+    let mut poll_state = PollState::default();
+    if args.simulate {
+        poll_state.simulated = Some(simulated_location());
+    }
+    if let Some(path) = &args.first_availability_file {
+        poll_state.alerted = load_saved(path);
+    }
+    if let Some(path) = &args.state_file {
+        poll_state.current_info = load_saved(path);
+    }
+    let mut stats = Stats::new();
+    let mut trend = Trend::default();
+    let area = AreaFilter {
+        zips: fetch_considered_zips(args.zips_path.as_deref())?,
+        regions: args.zip_neighbors.clone(),
+        radii: radii(&args.near, &args.radius_miles)?,
+    };
+    let mut schedule = Schedule::new(&args.state);
+    let client = build_client(&args)?;
+    let metrics = Arc::new(metrics::Metrics::default());
+    let metrics_server = args
+        .metrics_port
+        .map(|port| metrics::serve(port, metrics.clone()))
+        .transpose()?;
+    #[cfg(feature = "tui")]
+    let mut dashboard = if args.tui {
+        Some(tui::Dashboard::new()?)
+    } else {
+        None
+    };
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut startup_failures = 0;
+    let mut polled = false;
+    loop {
+        stats.polls += 1;
+        let due = schedule.due();
+        let mut retrying_startup = false;
+        let span = tracing::info_span!("cycle", state = %states_label(&due), cycle = stats.polls);
+        let outcome = tokio::select! {
+            _ = &mut shutdown => break,
+            outcome = run_cycle(&due, &mut poll_state, &client, &area, &args)
+                .instrument(span) => outcome,
+        };
+        match outcome {
+            CycleOutcome::Polled {
+                reported,
+                available,
+                failed,
+            } => {
+                // one state down from the start is as likely a bad config as all of them
+                if args.fail_fast && !polled && !failed.is_empty() {
+                    return Err(startup_error(&failed));
+                }
+                polled = true;
+                let fetched: Vec<String> = due
+                    .iter()
+                    .filter(|state| !failed.iter().any(|(failed, _)| failed == *state))
+                    .cloned()
+                    .collect();
+                schedule.polled(&fetched, &args.interval);
+                reschedule_failed(&failed, &mut schedule, &args);
+                log::info!("polled {}: {}", states_label(&fetched), reported.counts);
+                stats.record(&reported);
+                stats.failures += failed.len() as u64;
+                metrics.polled(available.len(), failed.len());
+                if let (Some(url), false) = (&args.healthcheck_url, args.dry_run) {
+                    tokio::select! {
+                        _ = &mut shutdown => break,
+                        _ = ping_healthcheck(&client, url) => {}
+                    }
+                }
+                let available: Vec<&Properties> = available.iter().collect();
+                if let Some(every) = args.report_interval_summary {
+                    trend.record(&available, &reported);
+                    if trend.cycles >= every {
+                        log::info!("{}", trend);
+                        trend.reset();
+                    }
+                }
+                #[cfg(feature = "tui")]
+                if let Some(dashboard) = &mut dashboard {
+                    dashboard.update(&available)?;
+                }
+            }
+            // a first poll that keeps failing is more likely a bad `--state`,
+            // `--api-base` or no network than the api being down, so say so
+            // instead of quietly retrying forever
+            CycleOutcome::FetchFailed(failed) if !polled => {
+                startup_failures += 1;
+                if args.fail_fast
+                    || startup_failures >= STARTUP_ATTEMPTS
+                    || failed.iter().any(|(_, e)| e.is_client_error())
+                {
+                    return Err(startup_error(&failed));
+                }
+                stats.failures += failed.len() as u64;
+                metrics.failed(failed.len());
+                retrying_startup = true;
+                let wait = failed
+                    .iter()
+                    .filter_map(|(_, e)| e.retry_after())
+                    .max()
+                    .unwrap_or(STARTUP_RETRY_DELAY);
+                schedule.after(&due, |_| wait);
+                log::warn!(
+                    "Failed to request appointments, retrying in {}s ({}/{}): {}",
+                    wait.as_secs(),
+                    startup_failures,
+                    STARTUP_ATTEMPTS - 1,
+                    joined_errors(&failed)
+                );
+            }
+            CycleOutcome::FetchFailed(failed) => {
+                stats.failures += failed.len() as u64;
+                metrics.failed(failed.len());
+                reschedule_failed(&failed, &mut schedule, &args);
+            }
+            CycleOutcome::Panicked(msg) => {
+                log::error!("Panicked while processing new appointments: {}", msg);
+                stats.failures += 1;
+                metrics.failed(1);
+                schedule.after(&due, |state| args.interval.for_state(state));
+            }
+        }
+        #[cfg(feature = "tui")]
+        if let (Some(dashboard), Some(e)) = (&mut dashboard, logging::take_last_error()) {
+            dashboard.failed(e)?;
+        }
+        if args.once && !retrying_startup {
+            break;
+        }
+        let wait = schedule.wait();
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = &mut dashboard {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                cmd = dashboard.wait(wait) => match cmd? {
+                    tui::Command::Quit => break,
+                    tui::Command::Refresh => schedule.all_due(),
+                    tui::Command::Poll => {}
+                },
+            }
+            continue;
+        }
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+    #[cfg(feature = "tui")]
+    drop(dashboard);
+    log::info!("shutting down");
+    if let Some(server) = metrics_server {
+        server.stop().await;
+    }
+    if let Some(path) = args.state_file_to_save() {
+        if let Err(e) = save(path, &poll_state.current_info) {
+            log::error!("Failed to save appointments to {}: {}", path.display(), e);
+        }
+    }
+    eprintln!("{}", stats);
+    Ok(())
+}
+
+/// Why the first poll of the `failed` states didn't work
+fn startup_error(failed: &[(String, FetchError)]) -> Box<dyn std::error::Error> {
+    let states: Vec<String> = failed.iter().map(|(state, _)| state.clone()).collect();
+    format!(
+        "Failed to request appointments for {} at startup, check --state, \
+         --api-base and the network: {}",
+        states_label(&states),
+        joined_errors(failed)
+    )
+    .into()
+}
+
+fn joined_errors(failed: &[(String, FetchError)]) -> String {
+    failed
+        .iter()
+        .map(|(_, e)| e.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Log each state that couldn't be fetched and back it off on its own
+fn reschedule_failed(failed: &[(String, FetchError)], schedule: &mut Schedule, args: &Args) {
+    for (state, e) in failed {
+        let wait = schedule.failed(state, e.retry_after(), &args.interval);
+        log::error!(
+            "Failed to request new appointments, polling {} again in {}s: {}",
+            state.to_uppercase(),
+            wait.as_secs(),
+            e
+        );
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM which is how containers are usually stopped
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+                return;
+            }
+            Err(e) => log::warn!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    ctrl_c().await
+}
+
+async fn ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::warn!("Failed to listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await
+    }
+}
+
+/// What's carried from one poll to the next
+#[derive(Default)]
+struct PollState {
+    /// The appointments each location had in the last poll
+    current_info: HashMap<u64, Vec<Appointment>>,
+    /// If each location had appointments for all doses in the last poll
+    all_doses_info: HashMap<u64, bool>,
+    /// A made up location to add to the next report, see `--simulate`
+    simulated: Option<Properties>,
+    /// Every location that has ever been reported, see `--first-availability-only`
+    alerted: HashSet<u64>,
+    /// When each location was last reported, see `--cooldown-mins`
+    last_alerted: HashMap<u64, Instant>,
+    /// A hash of each location's block in the last report printed to stdout
+    printed: HashMap<u64, u64>,
+    /// Every location written to a JSON report so far, see `--locations-cache`
+    described: HashSet<u64>,
+    /// When each channel last sent something, see `--notify-cooldown-per-channel`
+    last_sent: HashMap<Channel, Instant>,
+    /// The location each booking url was last reported for and when, see
+    /// `--dedupe-by`
+    reported_urls: HashMap<String, (u64, Instant)>,
+    /// The filtered locations from each state's last response, states are
+    /// polled on their own cadence so not every state is in every poll
+    latest: HashMap<String, Vec<Feature>>,
+}
+
+/// Load something saved by `--state-file` or `--first-availability-file`,
+/// a missing or unreadable file starts from scratch
+fn load_saved<T: DeserializeOwned + Default>(path: &Path) -> T {
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return T::default(),
+        Err(e) => {
+            log::warn!("Failed to read {}: {}", path.display(), e);
+            return T::default();
+        }
+    };
+    serde_json::from_str(&s).unwrap_or_else(|e| {
+        log::warn!("Failed to parse {}: {}", path.display(), e);
+        T::default()
+    })
+}
+
+fn save(path: &Path, value: &impl Serialize) -> R<()> {
+    output::write_atomic(path, &serde_json::to_string(value)?)?;
+    Ok(())
+}
+
+fn save_alerted(path: &Path, alerted: &HashSet<u64>) -> R<()> {
+    let mut ids: Vec<u64> = alerted.iter().copied().collect();
+    ids.sort_unstable();
+    save(path, &ids)
+}
+
+/// How a single poll went
+enum CycleOutcome {
+    Polled {
+        /// What was sent out for this poll
+        reported: Reported,
+        /// Every location in the target area with appointments after filtering
+        available: Vec<Properties>,
+        /// The states that couldn't be fetched, which the rest of the poll
+        /// went ahead without
+        failed: Vec<(String, FetchError)>,
+    },
+    /// None of the states' appointments could be fetched or parsed
+    FetchFailed(Vec<(String, FetchError)>),
+    /// Processing the appointments panicked, the `PollState` was left as it was
+    Panicked(String),
+}
+
+/// Fetch, filter and report on the current appointments for `states`, updating
+/// `poll_state` for the next poll
+async fn run_cycle(
+    states: &[String],
+    poll_state: &mut PollState,
+    client: &reqwest::Client,
+    area: &AreaFilter,
+    args: &Args,
+) -> CycleOutcome {
+    let Fetched {
+        features: mut fetched,
+        failed,
+    } = fetch_states(client, states, args).await;
+    if fetched.is_empty() && !failed.is_empty() {
+        return CycleOutcome::FetchFailed(failed);
+    }
+    log::info!("new appoints received");
+    // a panic while formatting malformed data shouldn't take
+    // down the whole monitor, skip this poll and try again
+    let simulated = poll_state.simulated.take();
+    let received = fetched.iter().map(|(_, features)| features.len()).sum();
+    let processed = AssertUnwindSafe(async {
+        for (_, features) in &mut fetched {
+            filter_locations(features, args);
+            filter_appointments(features, args);
+            if args.include_distance_in_alerts {
+                for f in features {
+                    f.properties.miles_away = miles_from_near(f, &args.near);
+                }
+            }
+        }
+        let features: Vec<Feature> = fetched
+            .iter()
+            .flat_map(|(_, features)| features.iter().cloned())
+            .collect();
+        let reported = report_locations(
+            &features,
+            received,
+            simulated.as_ref(),
+            poll_state,
+            area,
+            client,
+            args,
+        )
+        .await;
+        (features, reported)
+    })
+    .catch_unwind()
+    .await;
+    let (features, reported) = match processed {
+        Ok(processed) => processed,
+        Err(panic) => return CycleOutcome::Panicked(panic_message(&panic).to_string()),
+    };
+    if args.first_availability_only && reported.alerts > 0 {
+        poll_state.alerted.extend(&reported.location_ids);
+        if let Some(path) = &args.first_availability_file {
+            if let Err(e) = save_alerted(path, &poll_state.alerted) {
+                log::error!(
+                    "Failed to save alerted locations to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+    if args.cooldown_mins.is_some() && reported.alerts > 0 {
+        let now = Instant::now();
+        for id in &reported.location_ids {
+            poll_state.last_alerted.insert(*id, now);
+        }
+    }
+    // only the states that were fetched are replaced, the locations of a
+    // state that wasn't due or failed keep what they had so they don't look
+    // new when it's next fetched
+    if args
+        .state
+        .iter()
+        .all(|state| fetched.iter().any(|(fetched, _)| fetched == state))
+    {
+        poll_state.all_doses_info.clear();
+        poll_state.current_info.clear();
+    }
+    for (state, features) in fetched {
+        let old = poll_state.latest.insert(state, features);
+        for f in old.into_iter().flatten() {
+            poll_state.all_doses_info.remove(&f.properties.id);
+            poll_state.current_info.remove(&f.properties.id);
+        }
+    }
+    let latest: Vec<Feature> = args
+        .state
+        .iter()
+        .filter_map(|state| poll_state.latest.get(state))
+        .flatten()
+        .cloned()
+        .collect();
+    let available = available_locations(&latest, area)
+        .into_iter()
+        .cloned()
+        .collect();
+    for f in features {
+        let props = f.properties;
+        poll_state.all_doses_info.insert(
+            props.id,
+            props.appointments_available_all_doses == Some(true),
+        );
+        poll_state
+            .current_info
+            .insert(props.id, props.appointments.unwrap_or_default());
+    }
+    if let Some(path) = args.state_file_to_save() {
+        if let Err(e) = save(path, &poll_state.current_info) {
+            log::error!("Failed to save appointments to {}: {}", path.display(), e);
+        }
+    }
+    CycleOutcome::Polled {
+        reported,
+        available,
+        failed,
+    }
+}
+
+/// What `fetch_states` got for each state, both in the order the states
+/// were given
+struct Fetched {
+    features: Vec<(String, Vec<Feature>)>,
+    failed: Vec<(String, FetchError)>,
+}
+
+/// Fetch `states`, up to `--max-concurrency` at a time. Location ids are
+/// unique across states so the locations of every state can be tracked
+/// together.
+async fn fetch_states(client: &reqwest::Client, states: &[String], args: &Args) -> Fetched {
+    let mut results: Vec<_> = futures::stream::iter(states.iter().enumerate())
+        .map(|(i, state)| async move {
+            let url = args.state_url(state);
+            let assumed = args.assume_timezone.or_else(|| states::timezone(state));
+            let res = fetch_state(client, &url, args.parse_retries, assumed).await;
+            (i, res)
+        })
+        .buffer_unordered(args.max_concurrency)
+        .collect()
+        .await;
+    results.sort_by_key(|(i, _)| *i);
+    let mut fetched = Fetched {
+        features: Vec::new(),
+        failed: Vec::new(),
+    };
+    // every error names the state's url, and is kept intact so the poll loop
+    // can tell what kind of failure it was
+    for (i, res) in results {
+        match res {
+            Ok(res) => fetched.features.push((states[i].clone(), res.features)),
+            Err(e) => fetched.failed.push((states[i].clone(), e)),
+        }
+    }
+    fetched
+}
+
+/// The longest to wait between polls while the api keeps failing
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// How many times the first poll is tried before giving up
+const STARTUP_ATTEMPTS: u32 = 3;
+/// How long to wait between tries of the first poll
+const STARTUP_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Double the wait after each failed poll in a row, up to `MAX_BACKOFF`
+/// unless `interval` is already longer
+fn backoff(interval: Duration, failures_in_a_row: u32) -> Duration {
+    let backed_off = interval
+        .checked_mul(2u32.saturating_pow(failures_in_a_row))
+        .unwrap_or(MAX_BACKOFF);
+    backed_off.min(MAX_BACKOFF).max(interval)
+}
+
+/// How long to give a healthcheck ping before giving up on it
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Let an external dead man's switch know a poll succeeded
+async fn ping_healthcheck(client: &reqwest::Client, url: &str) {
+    match client.get(url).timeout(HEALTHCHECK_TIMEOUT).send().await {
+        Ok(res) if !res.status().is_success() => {
+            log::warn!("Healthcheck ping to {} returned {}", url, res.status())
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Failed to ping healthcheck {}: {}", url, e),
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Build the client shared by every poll so connections
+/// are reused from one cycle to the next
+fn build_client(args: &Args) -> R<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .pool_idle_timeout(None)
+        .tcp_keepalive(Duration::from_secs(60));
+    if args.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    Ok(builder.build()?)
+}
+
+/// Running totals for a session, printed when the poll loop exits
+struct Stats {
+    started: Instant,
+    polls: u64,
+    failures: u64,
+    appointments_seen: u64,
+    alerts_sent: u64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            polls: 0,
+            failures: 0,
+            appointments_seen: 0,
+            alerts_sent: 0,
+        }
+    }
+
+    fn record(&mut self, reported: &Reported) {
+        self.appointments_seen += reported.appointments as u64;
+        self.alerts_sent += reported.alerts as u64;
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let uptime = self.started.elapsed().as_secs();
+        write!(
+            f,
+            "polls: {}, failures: {}, appointments seen: {}, alerts sent: {}, uptime: {}h{:02}m{:02}s",
+            self.polls,
+            self.failures,
+            self.appointments_seen,
+            self.alerts_sent,
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+        )
+    }
+}
+
+/// Availability across the polls since the last `--report-interval-summary` line
+#[derive(Default)]
+struct Trend {
+    cycles: u64,
+    matching_locations: u64,
+    peak_slots: usize,
+    /// Not reset with the rest of the window
+    cycles_since_new: u64,
+}
+
+impl Trend {
+    fn record(&mut self, matching: &[&Properties], reported: &Reported) {
+        self.cycles += 1;
+        self.matching_locations += matching.len() as u64;
+        let slots = matching
+            .iter()
+            .map(|props| props.appointments.as_ref().map(Vec::len).unwrap_or(0))
+            .sum();
+        self.peak_slots = self.peak_slots.max(slots);
+        if reported.appointments > 0 {
+            self.cycles_since_new = 0;
+        } else {
+            self.cycles_since_new += 1;
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self {
+            cycles_since_new: self.cycles_since_new,
+            ..Self::default()
+        };
+    }
+}
+
+impl std::fmt::Display for Trend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "last {} polls: {:.1} matching locations on average, peak of {} open slots, {} polls since new appointments",
+            self.cycles,
+            self.matching_locations as f64 / self.cycles.max(1) as f64,
+            self.peak_slots,
+            self.cycles_since_new,
+        )
+    }
+}
+
+/// What a single call to `report_locations` put in front of the user
+struct Reported {
+    /// The number of new appointments across all reported locations
+    appointments: usize,
+    /// The number of reports that were actually printed or sent
+    alerts: usize,
+    /// The ids of the reported locations
+    location_ids: Vec<u64>,
+    /// How many locations made it through each step of the poll
+    counts: PollCounts,
+}
+
+/// The number of locations at each step from the api's response to a report,
+/// logged after every poll so it's clear what the filters are doing
+struct PollCounts {
+    /// Every location in the responses, before any filtering
+    received: usize,
+    /// Locations left after `--store`, `--provider` and `--exclude-provider`
+    locations: usize,
+    /// Locations with any appointments
+    available: usize,
+    /// Locations with appointments that weren't there last poll
+    new: usize,
+    /// Locations that passed every filter and were reported
+    matched: usize,
+}
+
+impl std::fmt::Display for PollCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} locations received, {} after location filters, {} with availability, \
+             {} new since last poll, {} matched filters",
+            self.received, self.locations, self.available, self.new, self.matched
+        )
+    }
+}
+
+/// `received` is how many locations the responses had before `locations`
+/// was filtered
+async fn report_locations(
+    locations: &[Feature],
+    received: usize,
+    simulated: Option<&Properties>,
+    poll_state: &mut PollState,
+    area: &AreaFilter,
+    client: &reqwest::Client,
+    args: &Args,
+) -> Reported {
+    let current_info = &poll_state.current_info;
+    let mut matched = if args.show_all {
+        available_locations(locations, area)
+    } else if args.watch_all_doses {
+        all_doses_locations(locations, &poll_state.all_doses_info, area)
+    } else {
+        new_locations(locations, current_info, area)
+    };
+    if args.first_availability_only {
+        matched.retain(|props| !poll_state.alerted.contains(&props.id));
+    }
+    if let Some(mins) = args.cooldown_mins {
+        let cooldown = Duration::from_secs(mins.saturating_mul(60));
+        matched.retain(|props| {
+            poll_state
+                .last_alerted
+                .get(&props.id)
+                .is_none_or(|last| last.elapsed() >= cooldown)
+        });
+    }
+    if args.min_appointments > 1 {
+        matched.retain(|props| meets_min_appointments(props, current_info, args));
+    }
+    sort_locations(&mut matched, locations, current_info, args);
+    sort_by_provider_priority(&mut matched, &args.provider_priority);
+    if args.dedupe_by == Dedupe::Url {
+        let window = url_window(args);
+        let reported_urls = &poll_state.reported_urls;
+        let mut seen = HashSet::new();
+        matched.retain(|props| {
+            let url = match &props.url {
+                Some(url) => url,
+                None => return true,
+            };
+            // states polled in different cycles can still list the same url
+            let recent = reported_urls
+                .get(url)
+                .is_some_and(|(id, at)| *id != props.id && at.elapsed() < window);
+            !recent && seen.insert(url)
+        });
+    }
+    let location_ids: Vec<u64> = matched.iter().map(|props| props.id).collect();
+    let counts = PollCounts {
+        received,
+        locations: locations.len(),
+        available: locations
+            .iter()
+            .filter(|f| {
+                f.properties
+                    .appointments
+                    .as_ref()
+                    .is_some_and(|a| !a.is_empty())
+            })
+            .count(),
+        new: locations
+            .iter()
+            .filter(|f| count_new_appts(&f.properties, current_info) > 0)
+            .count(),
+        matched: location_ids.len(),
+    };
+    if let (Some(path), false) = (&args.history_csv, args.dry_run) {
+        if let Err(e) = history::record(path, &matched, current_info) {
+            log::error!("Failed to write history to {}: {}", path.display(), e);
+        }
+    }
+    if let Some(simulated) = simulated {
+        matched.insert(0, simulated);
+    }
+    let mut reported = Reported {
+        appointments: matched
+            .iter()
+            .map(|props| count_new_appts(props, current_info))
+            .sum(),
+        alerts: 0,
+        location_ids,
+        counts,
+    };
+    if args.notify_cleared {
+        let cleared = cleared_locations(locations, current_info, area);
+        if !cleared.is_empty() {
+            send_cleared(&cleared, &mut poll_state.last_sent, client, args).await;
+        }
+    }
+    if matched.is_empty() {
+        return reported;
+    }
+    #[cfg(feature = "qr")]
+    if let Some(dir) = &args.qr {
+        if let Err(e) = qr::write_codes(dir, &matched) {
+            log::error!("Failed to write QR codes to {}: {}", dir.display(), e);
+        }
+    }
+    #[cfg(feature = "desktop-notifications")]
+    if args.desktop && !args.dry_run && !throttled(Channel::Desktop, &poll_state.last_sent, args) {
+        desktop::notify_locations(&matched);
+        poll_state
+            .last_sent
+            .insert(Channel::Desktop, Instant::now());
+    }
+    match args.alert_granularity {
+        Granularity::Combined => {
+            reported.alerts += send_report(&matched, poll_state, client, args).await as usize;
+        }
+        Granularity::PerLocation => {
+            for props in &matched {
+                reported.alerts += send_report(&[*props], poll_state, client, args).await as usize;
+            }
+        }
+    }
+    if args.dedupe_by == Dedupe::Url && reported.alerts > 0 {
+        let window = url_window(args);
+        let now = Instant::now();
+        let reported_urls = &mut poll_state.reported_urls;
+        reported_urls.retain(|_, (_, at)| at.elapsed() < window);
+        for props in matched
+            .iter()
+            .filter(|props| reported.location_ids.contains(&props.id))
+        {
+            if let Some(url) = &props.url {
+                reported_urls.insert(url.clone(), (props.id, now));
+            }
+        }
+    }
+    reported
+}
+
+/// How long a reported url hides other locations with it, `--cooldown-mins`
+/// or without one, long enough for every state to be polled again
+fn url_window(args: &Args) -> Duration {
+    match args.cooldown_mins {
+        Some(mins) => Duration::from_secs(mins.saturating_mul(60)),
+        None => args
+            .state
+            .iter()
+            .map(|state| args.interval.for_state(state))
+            .max()
+            .unwrap_or(args.interval.default),
+    }
+}
+
+/// If a location has at least `--min-appointments` new appointments, or
+/// appointments in total with `--show-all`
+fn meets_min_appointments(
+    props: &Properties,
+    current_info: &HashMap<u64, Vec<Appointment>>,
+    args: &Args,
+) -> bool {
+    let count = if args.show_all {
+        props.appointments.as_ref().map(Vec::len).unwrap_or(0)
+    } else {
+        count_new_appts(props, current_info)
+    };
+    count >= args.min_appointments
+}
+
+/// Send a single report of `locations` through whichever output is configured,
+/// returning if it went out
+async fn send_report(
+    locations: &[&Properties],
+    poll_state: &mut PollState,
+    client: &reqwest::Client,
+    args: &Args,
+) -> bool {
+    if args.dry_run {
+        if args.prints_reports() {
+            print_dry_run_banner("report", args);
+            print_locations(locations, args, &mut poll_state.described);
+        }
+        // nothing was sent, so nothing should be remembered as alerted
+        return false;
+    }
+    let channel = args.report_channel();
+    if let Some(channel) = channel.filter(|c| throttled(*c, &poll_state.last_sent, args)) {
+        log::info!("skipping a report, {} is cooling down", channel);
+        return false;
+    }
+    let sent = deliver_report(locations, poll_state, client, args).await;
+    if let (Some(channel), true) = (channel, sent) {
+        poll_state.last_sent.insert(channel, Instant::now());
+    }
+    sent
+}
+
+/// Send a report through the channel `Args::report_channel` picks
+async fn deliver_report(
+    locations: &[&Properties],
+    poll_state: &mut PollState,
+    client: &reqwest::Client,
+    args: &Args,
+) -> bool {
+    #[cfg(feature = "sms-notifications")]
+    if let (Some(sid), Some(token), Some(from), Some(to)) = (
+        &args.twilio_sid,
+        args.twilio_token.as_ref().map(Secret::expose),
+        &args.twilio_from,
+        &args.sms_to,
+    ) {
+        return match sms::sms_locations(locations, client, sid, token, from, to).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to text report to {}: {}", to, e);
+                false
+            }
+        };
+    }
+    #[cfg(feature = "telegram-notifications")]
+    if let (Some(token), Some(chat_id)) = (
+        args.telegram_token.as_ref().map(Secret::expose),
+        &args.telegram_chat_id,
+    ) {
+        return match telegram::telegram_locations(locations, client, token, chat_id).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to send report to Telegram chat {}: {}", chat_id, e);
+                false
+            }
+        };
+    }
+    if let Some(url) = &args.webhook_url {
+        match webhook_text(client, url, &render_report(locations, args, false)).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to post report to webhook: {}", e);
+                false
+            }
+        }
+    } else if let (Some(from_email), Some(to_email)) = (&args.from_email, &args.to_email) {
+        match email_locations(
+            locations,
+            args,
+            from_email,
+            to_email,
+            args.envelope_from.as_deref(),
+            &email_subject(locations, &poll_state.current_info, args),
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!(
+                    "Failed to send email from {} to {}: {}",
+                    from_email,
+                    to_email,
+                    e
+                );
+                false
+            }
+        }
+    } else if let Some(path) = &args.output {
+        let report = match args.format {
+            ReportFormat::Text => render_report(locations, args, false),
+            ReportFormat::Json => render_json(locations, args, &mut poll_state.described),
+        };
+        match output::append(path, args.output_rotate, &report) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to write report to {}: {}", path.display(), e);
+                false
+            }
+        }
+    } else if args.prints_reports() {
+        // `--show-all` asks for every location every poll, repeats included
+        if args.show_all {
+            print_locations(locations, args, &mut poll_state.described);
+            return true;
+        }
+        let changed = changed_since_printed(locations, &mut poll_state.printed, args);
+        if changed.is_empty() {
+            log::debug!("skipping a report identical to the last one printed");
+            return false;
+        }
+        print_locations(&changed, args, &mut poll_state.described);
+        true
+    } else {
+        false
+    }
+}
+
+/// A subject that says enough to act on from a phone's notification, which
+/// often shows nothing else
+fn email_subject(
+    locations: &[&Properties],
+    current_info: &HashMap<u64, Vec<Appointment>>,
+    args: &Args,
+) -> String {
+    let kind = if args.show_all { "" } else { "new " };
+    match locations {
+        [props] => {
+            let count = if args.show_all {
+                props.appointments.as_ref().map(Vec::len).unwrap_or(0)
+            } else {
+                count_new_appts(props, current_info)
+            };
+            format!(
+                "Vaccine: {} in {}, {} \u{2014} {} {}slot{}",
+                string_or_question(&props.provider),
+                string_or_question(&props.city),
+                string_or_question(&props.state),
+                count,
+                kind,
+                if count == 1 { "" } else { "s" }
+            )
+        }
+        _ => format!(
+            "Vaccine: {} locations with {}appointments",
+            locations.len(),
+            kind
+        ),
+    }
+}
+
+/// Drop the locations that would print exactly what they did last time,
+/// which happens when the api reorders or re-sends the same times, and
+/// remember what the rest will print
+fn changed_since_printed<'a>(
+    locations: &[&'a Properties],
+    printed: &mut HashMap<u64, u64>,
+    args: &Args,
+) -> Vec<&'a Properties> {
+    use std::hash::{Hash, Hasher};
+    locations
+        .iter()
+        .copied()
+        .filter(|props| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            props
+                .display(args.appointments_sort)
+                .to_string()
+                .hash(&mut hasher);
+            printed.insert(props.id, hasher.finish()) != Some(hasher.finish())
+        })
+        .collect()
+}
+
+/// Send a notice that the locations no longer have appointments through the
+/// same channel as reports
+async fn send_cleared(
+    locations: &[&Properties],
+    last_sent: &mut HashMap<Channel, Instant>,
+    client: &reqwest::Client,
+    args: &Args,
+) -> bool {
+    let text = render_cleared(locations);
+    if args.dry_run {
+        if args.prints_reports() {
+            print_dry_run_banner("notice", args);
+            print_cleared(locations, text, args);
+        }
+        return false;
+    }
+    let channel = args.report_channel();
+    if let Some(channel) = channel.filter(|c| throttled(*c, last_sent, args)) {
+        log::info!("skipping a notice, {} is cooling down", channel);
+        return false;
+    }
+    let sent = deliver_cleared(locations, text, client, args).await;
+    if let (Some(channel), true) = (channel, sent) {
+        last_sent.insert(channel, Instant::now());
+    }
+    sent
+}
+
+/// Send a notice through the channel `Args::report_channel` picks
+async fn deliver_cleared(
+    locations: &[&Properties],
+    text: String,
+    client: &reqwest::Client,
+    args: &Args,
+) -> bool {
+    #[cfg(feature = "sms-notifications")]
+    if let (Some(sid), Some(token), Some(from), Some(to)) = (
+        &args.twilio_sid,
+        args.twilio_token.as_ref().map(Secret::expose),
+        &args.twilio_from,
+        &args.sms_to,
+    ) {
+        return match sms::sms_text(client, sid, token, from, to, &text).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to text notice to {}: {}", to, e);
+                false
+            }
+        };
+    }
+    #[cfg(feature = "telegram-notifications")]
+    if let (Some(token), Some(chat_id)) = (
+        args.telegram_token.as_ref().map(Secret::expose),
+        &args.telegram_chat_id,
+    ) {
+        return match telegram::telegram_text(client, token, chat_id, &text).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to send notice to Telegram chat {}: {}", chat_id, e);
+                false
+            }
+        };
+    }
+    if let Some(url) = &args.webhook_url {
+        match webhook_text(client, url, &text).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to post notice to webhook: {}", e);
+                false
+            }
+        }
+    } else if let (Some(from_email), Some(to_email)) = (&args.from_email, &args.to_email) {
+        match email_text(
+            args,
+            from_email,
+            to_email,
+            args.envelope_from.as_deref(),
+            "Vaccine Appointments No Longer Available",
+            text,
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!(
+                    "Failed to send email from {} to {}: {}",
+                    from_email,
+                    to_email,
+                    e
+                );
+                false
+            }
+        }
+    } else if let Some(path) = &args.output {
+        let notice = match args.format {
+            ReportFormat::Text => text,
+            ReportFormat::Json => render_cleared_json(locations, args),
+        };
+        match output::append(path, args.output_rotate, &notice) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Failed to write notice to {}: {}", path.display(), e);
+                false
+            }
+        }
+    } else if args.prints_reports() {
+        print_cleared(locations, text, args);
+        true
+    } else {
+        false
+    }
+}
+
+/// How log messages are written, see `--log-format`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected `pretty` or `json`, found {:?}", s)),
+        }
+    }
+}
+
+/// If the new locations found in a poll are sent together or one at a time
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Granularity {
+    Combined,
+    PerLocation,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "combined" => Ok(Self::Combined),
+            "per-location" => Ok(Self::PerLocation),
+            _ => Err(format!(
+                "expected `combined` or `per-location`, found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// A made up location with appointments over the next few days, used by `--simulate`
+/// to check every configured output end to end
+fn simulated_location() -> Properties {
+    let now = Local::now();
+    Properties {
+        id: 0,
+        url: Some("https://www.vaccinespotter.org/".to_string()),
+        city: Some("Nowhere".to_string()),
+        state: Some("??".to_string()),
+        address: Some("SIMULATED by --simulate, this is not a real appointment".to_string()),
+        name: Some("SIMULATED LOCATION".to_string()),
+        provider: Some("vaccine_spotter".to_string()),
+        postal_code: Some("00000".to_string()),
+        provider_location_id: None,
+        carries_vaccine: Some(true),
+        appointments_available: Some(true),
+        appointments_available_all_doses: Some(true),
+        appointments_available_2nd_dose_only: Some(false),
+        appointments: Some(
+            (1..=3)
+                .map(|days| Appointment {
+                    time: now + chrono::Duration::days(days),
+                    kind: None,
+                    vaccine_types: Vec::new(),
+                    appointment_types: Vec::new(),
+                })
+                .collect(),
+        ),
+        time_zone: None,
+        miles_away: None,
+    }
+}
+
+/// Drop any locations that don't pass the location filters
+fn filter_locations(features: &mut Vec<Feature>, args: &Args) {
+    if !args.store.is_empty() {
+        features.retain(|f| {
+            f.properties
+                .provider_location_id
+                .as_ref()
+                .is_some_and(|id| args.store.contains(id))
+        });
+    }
+    if !args.provider.is_empty() || !args.exclude_provider.is_empty() {
+        features.retain(|f| {
+            provider_matches(
+                f.properties.provider.as_deref(),
+                &args.provider,
+                &args.exclude_provider,
+            )
+        });
+    }
+    match args.dose {
+        Dose::All => {}
+        Dose::First => {
+            features.retain(|f| f.properties.appointments_available_all_doses == Some(true))
+        }
+        Dose::Second => {
+            features.retain(|f| f.properties.appointments_available_2nd_dose_only == Some(true))
+        }
+    }
+}
+
+/// If a provider contains one of `include`, when there are any, and none of
+/// `exclude`, ignoring case
+fn provider_matches(provider: Option<&str>, include: &[String], exclude: &[String]) -> bool {
+    let provider = provider.unwrap_or_default().to_lowercase();
+    let contains = |name: &String| provider.contains(&name.to_lowercase());
+    (include.is_empty() || include.iter().any(contains)) && !exclude.iter().any(contains)
+}
+
+/// Which doses a location needs to offer to be considered, see `--dose`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dose {
+    All,
+    First,
+    Second,
+}
+
+impl FromStr for Dose {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "first" => Ok(Self::First),
+            "second" => Ok(Self::Second),
+            _ => Err(format!(
+                "expected `all`, `first` or `second`, found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Drop any appointments that don't pass the appointment filters, a location
+/// left without any appointments will not be reported
+fn filter_appointments(features: &mut [Feature], args: &Args) {
+    for feature in features {
+        let tz = feature.properties.timezone();
+        if let Some(appointments) = &mut feature.properties.appointments {
+            appointments.retain(|appt| appointment_matches(appt, tz, args));
+        }
+    }
+}
+
+/// `tz` is the location's timezone, the day and time of day filters go by
+/// its clock like the reports do
+fn appointment_matches(appt: &Appointment, tz: Option<Tz>, args: &Args) -> bool {
+    let local = match tz {
+        Some(tz) => appt.time.with_timezone(&tz).naive_local(),
+        None => appt.time.naive_local(),
+    };
+    let weekend = matches!(local.weekday(), Weekday::Sat | Weekday::Sun);
+    if args.only_weekends && !weekend {
+        return false;
+    }
+    if args.only_weekdays && weekend {
+        return false;
+    }
+    if let Some(days) = args.max_days_out {
+        if appt.time > Local::now() + chrono::Duration::days(i64::from(days)) {
+            return false;
+        }
+    }
+    if let Some(vaccine) = &args.vaccine {
+        if !appt.is_for_vaccine(vaccine) {
+            return false;
+        }
+    }
+    in_window(local.time(), args.after, args.before)
+}
+
+/// If `time` falls between `after` and `before`, wrapping past midnight
+/// when `after` is the later of the two
+fn in_window(time: NaiveTime, after: Option<NaiveTime>, before: Option<NaiveTime>) -> bool {
+    match (after, before) {
+        (Some(after), Some(before)) if after > before => time >= after || time < before,
+        (after, before) => {
+            after.is_none_or(|after| time >= after) && before.is_none_or(|before| time < before)
+        }
+    }
+}
+
+fn time_of_day(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M")
+        .map_err(|e| format!("expected a time like `09:30`, found {:?}: {}", s, e))
+}
+
+/// Collect the locations in the target area that have appointments
+/// not seen in the previous poll
+fn new_locations<'a>(
+    locations: &'a [Feature],
+    current_info: &HashMap<u64, Vec<Appointment>>,
+    area: &AreaFilter,
+) -> Vec<&'a Properties> {
+    let mut ret = Vec::new();
+    for entry in locations {
+        if let Some(appointments) = &entry.properties.appointments {
+            let is_new = if let Some(info) = current_info.get(&entry.properties.id) {
+                contains_new_appts(appointments, info)
+            } else {
+                !appointments.is_empty()
+            };
+            if is_new && area.matches(entry) {
+                ret.push(&entry.properties);
+            }
+        }
+    }
+    ret
+}
+
+/// Collect the locations in the target area that now have appointments
+/// for all doses but didn't in the previous poll
+fn all_doses_locations<'a>(
+    locations: &'a [Feature],
+    all_doses_info: &HashMap<u64, bool>,
+    area: &AreaFilter,
+) -> Vec<&'a Properties> {
+    locations
+        .iter()
+        .filter(|entry| area.matches(entry))
+        .map(|entry| &entry.properties)
+        .filter(|props| props.appointments_available_all_doses == Some(true))
+        .filter(|props| !all_doses_info.get(&props.id).copied().unwrap_or(false))
+        .filter(|props| props.appointments.as_ref().is_some_and(|a| !a.is_empty()))
+        .collect()
+}
+
+/// Collect every location in the target area with any appointments,
+/// new or not
+fn available_locations<'a>(locations: &'a [Feature], area: &AreaFilter) -> Vec<&'a Properties> {
+    locations
+        .iter()
+        .filter(|entry| area.matches(entry))
+        .map(|entry| &entry.properties)
+        .filter(|props| props.appointments.as_ref().is_some_and(|a| !a.is_empty()))
+        .collect()
+}
+
+/// Order the locations by `--sort`, anything missing what it's sorted by
+/// goes last
+fn sort_locations(
+    matched: &mut [&Properties],
+    locations: &[Feature],
+    current_info: &HashMap<u64, Vec<Appointment>>,
+    args: &Args,
+) {
+    match args.sort {
+        LocationSort::Soonest => matched.sort_by_key(|props| {
+            let appointments = props.appointments.iter().flatten();
+            // with `--show-all` a location might not have anything new
+            let next = appointments
+                .clone()
+                .filter(|appt| {
+                    current_info
+                        .get(&props.id)
+                        .is_none_or(|old| !old.contains(appt))
+                })
+                .map(|appt| appt.time)
+                .min()
+                .or_else(|| appointments.map(|appt| appt.time).min());
+            (next.is_none(), next)
+        }),
+        LocationSort::Distance => {
+            let miles: HashMap<u64, f64> = locations
+                .iter()
+                .filter_map(|f| Some((f.properties.id, miles_from_near(f, &args.near)?)))
+                .collect();
+            matched.sort_by(|a, b| match (miles.get(&a.id), miles.get(&b.id)) {
+                (Some(a), Some(b)) => a.total_cmp(b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            });
+        }
+        LocationSort::Name => matched.sort_by_key(|props| {
+            format!(
+                "{}-{}",
+                string_or_question(&props.provider),
+                string_or_question(&props.name)
+            )
+            .to_lowercase()
+        }),
+    }
+}
+
+/// What makes two locations the same one, see `--dedupe-by`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dedupe {
+    Id,
+    Url,
+}
+
+impl FromStr for Dedupe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "id" => Ok(Self::Id),
+            "url" => Ok(Self::Url),
+            _ => Err(format!("expected `id` or `url`, found {:?}", s)),
+        }
+    }
+}
+
+/// How reported locations are ordered, see `--sort`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LocationSort {
+    Soonest,
+    Distance,
+    Name,
+}
+
+impl FromStr for LocationSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "soonest" => Ok(Self::Soonest),
+            "distance" => Ok(Self::Distance),
+            "name" => Ok(Self::Name),
+            _ => Err(format!(
+                "expected `soonest`, `distance` or `name`, found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Collect the locations in the target area that had appointments in the
+/// previous poll but don't anymore
+fn cleared_locations<'a>(
+    locations: &'a [Feature],
+    current_info: &HashMap<u64, Vec<Appointment>>,
+    area: &AreaFilter,
+) -> Vec<&'a Properties> {
+    locations
+        .iter()
+        .filter(|entry| {
+            let props = &entry.properties;
+            let had_appointments = current_info
+                .get(&props.id)
+                .is_some_and(|old| !old.is_empty());
+            let empty = props.appointments_available == Some(false)
+                || props.appointments.as_ref().is_none_or(Vec::is_empty);
+            had_appointments && empty && area.matches(entry)
+        })
+        .map(|entry| &entry.properties)
+        .collect()
+}
+
+/// Move the locations from providers in `priority` to the front, in the order
+/// the providers are listed, keeping the original order otherwise
+fn sort_by_provider_priority(locations: &mut [&Properties], priority: &[String]) {
+    if priority.is_empty() {
+        return;
+    }
+    let priority: Vec<String> = priority.iter().map(|p| p.to_lowercase()).collect();
+    locations.sort_by_key(|props| {
+        let provider = props.provider.as_deref().unwrap_or_default().to_lowercase();
+        priority
+            .iter()
+            .position(|p| provider.contains(p.as_str()))
+            .unwrap_or(priority.len())
+    });
+}
+
+/// The area to report on, a location in any of the zipcodes or within the
+/// radius is considered. If none are set every location is considered
+struct AreaFilter {
+    /// Zipcodes, or the first few digits of zipcodes
+    zips: Vec<String>,
+    /// The first 3 digits of a zipcode, a rough stand in for the area around it
+    regions: Vec<String>,
+    /// Distances from points, see `--near`
+    radii: Vec<Radius>,
+}
+
+impl AreaFilter {
+    fn matches(&self, feature: &Feature) -> bool {
+        let by_zip = !self.zips.is_empty() || !self.regions.is_empty();
+        if !by_zip && self.radii.is_empty() {
+            return true;
+        }
+        (by_zip && self.in_zips(&feature.properties)) || self.in_radius(feature)
+    }
+
+    fn in_zips(&self, props: &Properties) -> bool {
+        if let Some(zip) = &props.postal_code {
+            zip_matches(zip, &self.zips) || zip_matches(zip, &self.regions)
+        } else {
+            false
+        }
+    }
+
+    fn in_radius(&self, feature: &Feature) -> bool {
+        match &feature.geometry {
+            Some(geometry) => self
+                .radii
+                .iter()
+                .any(|radius| radius.contains(geometry.coordinate())),
+            None => false,
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> R<()> {
-    pretty_env_logger::init();
-    let args = Args::from_args();
-    log::debug!("starting with args: {:?}", args);
-    let mut current_info: HashMap<u64, Vec<Appointment>> = HashMap::new();
-    let zips = fetch_considered_zips(&args.zips_path);
-    loop {
-        if let Ok(res) = reqwest::get(&format!(
-            "https://www.vaccinespotter.org/api/v0/states/{}.json",
-            args.state.to_uppercase()
-        ))
-        .await
-        {
-            log::info!("requesting new appointments");
-            let res: Response = match res.json().await {
-                Ok(res) => res,
-                Err(e) => {
-                    log::error!("Failed to request new appointments: {}", e);
-                    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                    continue;
-                }
-            };
-            log::info!("new appoints received");
-            report_locations(
-                &res.features,
-                &current_info,
-                &zips,
-                &args.from_email,
-                &args.to_email,
-            );
-            current_info = res
-                .features
-                .into_iter()
-                .map(|f| {
-                    (
-                        f.properties.id,
-                        f.properties.appointments.unwrap_or_default(),
-                    )
-                })
-                .collect();
+/// Pair each `--near` with its `--radius-miles`, a single radius goes with
+/// every center
+fn radii(centers: &[Coordinate], miles: &[f64]) -> R<Vec<Radius>> {
+    let miles = match miles {
+        [miles] => vec![*miles; centers.len()],
+        miles if miles.len() == centers.len() => miles.to_vec(),
+        _ => {
+            return Err(format!(
+                "--radius-miles was provided {} times for {} --near, provide it once \
+                 or once for each --near",
+                miles.len(),
+                centers.len()
+            )
+            .into())
         }
-        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+    };
+    Ok(centers
+        .iter()
+        .zip(miles)
+        .map(|(center, miles)| Radius {
+            center: *center,
+            miles,
+        })
+        .collect())
+}
+
+/// How far a location is from the closest of `centers`, if it has coordinates
+fn miles_from_near(feature: &Feature, centers: &[Coordinate]) -> Option<f64> {
+    let point = feature.geometry.as_ref()?.coordinate();
+    centers
+        .iter()
+        .map(|center| center.miles_to(point))
+        .min_by(f64::total_cmp)
+}
+
+struct Radius {
+    center: Coordinate,
+    miles: f64,
+}
+
+impl Radius {
+    fn contains(&self, point: Coordinate) -> bool {
+        self.center.miles_to(point) <= self.miles
     }
 }
 
-fn report_locations(
-    locations: &[Feature],
-    current_info: &HashMap<u64, Vec<Appointment>>,
-    zips: &[String],
-    from_email: &Option<String>,
-    to_email: &Option<String>,
-) {
-    if let (Some(from_email), Some(to_email)) = (from_email, to_email) {
-        if let Err(e) = email_locations(locations, current_info, zips, from_email, to_email) {
-            eprintln!(
-                "Failed to send email from {} to {}: {}",
-                from_email, to_email, e
-            );
+/// If `zip` is one of `filters`, an entry shorter than a full zipcode
+/// matches every zipcode starting with it and a zip+4 matches its zipcode
+fn zip_matches(zip: &str, filters: &[String]) -> bool {
+    let zip = zip.split('-').next().unwrap_or(zip);
+    filters.iter().any(|filter| {
+        if filter.len() < 5 {
+            zip.starts_with(filter.as_str())
+        } else {
+            zip == filter
         }
-    } else {
-        print_locations(locations, current_info, zips)
+    })
+}
+
+/// Reduce a zipcode to the 3 digit prefix shared with its neighbors
+fn zip_region(zip: &str) -> Result<String, String> {
+    let zip = zip.trim();
+    if zip.len() < 3 || !zip.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("expected a zipcode, found {:?}", zip));
     }
+    Ok(zip[..3].to_string())
 }
 
 #[cfg(not(feature = "email-notifications"))]
 fn email_locations(
-    locations: &[Feature],
-    current_info: &HashMap<u64, Vec<Appointment>>,
-    zips: &[String],
+    locations: &[&Properties],
+    args: &Args,
     _from_email: &str,
     _to_email: &str,
+    _envelope_from: Option<&str>,
+    _subject: &str,
 ) -> R<()> {
-    print_locations(locations, current_info, zips);
+    // not part of the report stream, so it describes every location
+    print_locations(locations, args, &mut HashSet::new());
     Ok(())
 }
-fn print_locations(
-    locations: &[Feature],
-    current_info: &HashMap<u64, Vec<Appointment>>,
-    zips: &[String],
-) {
-    let mut printed_preamble = false;
-    for entry in locations {
-        if let Some(appointments) = &entry.properties.appointments {
-            if let Some(info) = current_info.get(&entry.properties.id) {
-                if contains_new_appts(appointments, info) {
-                    if let Some(zip) = &entry.properties.postal_code {
-                        if zips.is_empty() || zips.contains(zip) {
-                            if !printed_preamble {
-                                println!("{}", "=".repeat(10));
-                                println!("Report as of {}", chrono::Local::now());
-                                println!("{}", "=".repeat(10));
-                                printed_preamble = true
-                            }
-                            print_location(&entry.properties);
-                        }
-                    }
-                }
-            } else if !appointments.is_empty() {
-                if let Some(zip) = &entry.properties.postal_code {
-                    if zips.is_empty() || zips.contains(zip) {
-                        if !printed_preamble {
-                            println!("{}", "=".repeat(10));
-                            println!("Report as of {}", chrono::Local::now());
-                            println!("{}", "=".repeat(10));
-                            printed_preamble = true
-                        }
-                        print_location(&entry.properties);
-                    }
-                }
-            }
+
+#[cfg(not(feature = "email-notifications"))]
+fn email_text(
+    _args: &Args,
+    _from_email: &str,
+    _to_email: &str,
+    _envelope_from: Option<&str>,
+    _subject: &str,
+    body: String,
+) -> R<()> {
+    print!("{}", body);
+    Ok(())
+}
+
+/// `text` is the notice as `render_cleared` formats it
+fn print_cleared(locations: &[&Properties], text: String, args: &Args) {
+    match args.format {
+        ReportFormat::Text => print!("{}", text),
+        ReportFormat::Json => print!("{}", render_cleared_json(locations, args)),
+    }
+}
+
+/// Say that the `--dry-run` output that follows wasn't sent, JSON's goes to
+/// stderr so stdout can still be parsed
+fn print_dry_run_banner(what: &str, args: &Args) {
+    match args.format {
+        ReportFormat::Text => println!("[DRY RUN] this {} was not sent", what),
+        ReportFormat::Json => eprintln!("[DRY RUN] this {} was not sent", what),
+    }
+}
+
+fn print_locations(locations: &[&Properties], args: &Args, described: &mut HashSet<u64>) {
+    match args.format {
+        ReportFormat::Text => print!("{}", render_report(locations, args, use_color(args))),
+        ReportFormat::Json => print!("{}", render_json(locations, args, described)),
+    }
+}
+
+/// How reports are printed or written to `--output`, emails and webhooks
+/// are always text
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected `text` or `json`, found {:?}", s)),
+        }
+    }
+}
+
+/// A location as written by `--format json`
+#[derive(Serialize)]
+struct JsonLocation<'a> {
+    id: u64,
+    #[serde(flatten)]
+    details: JsonDetails<'a>,
+    appointments: Vec<DateTime<Local>>,
+}
+
+/// What `--locations-cache` only writes the first time a location is reported
+#[derive(Serialize)]
+struct JsonDetails<'a> {
+    name: Option<&'a str>,
+    provider: Option<&'a str>,
+    url: Option<&'a str>,
+    address: Option<&'a str>,
+    city: Option<&'a str>,
+    state: Option<&'a str>,
+    zip: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    miles_away: Option<f64>,
+}
+
+/// A report written with `--locations-cache`
+#[derive(Serialize)]
+struct CachedReport<'a> {
+    /// Only the locations that weren't in an earlier report
+    locations: BTreeMap<u64, JsonDetails<'a>>,
+    appointments: Vec<CachedAppointments>,
+}
+
+#[derive(Serialize)]
+struct CachedAppointments {
+    id: u64,
+    appointments: Vec<DateTime<Local>>,
+}
+
+impl<'a> JsonLocation<'a> {
+    fn new(props: &'a Properties, order: SortOrder) -> Self {
+        let mut appointments: Vec<DateTime<Local>> = props
+            .appointments
+            .iter()
+            .flatten()
+            .map(|appt| appt.time)
+            .collect();
+        order.sort(&mut appointments);
+        Self {
+            id: props.id,
+            details: JsonDetails {
+                name: props.name.as_deref(),
+                provider: props.provider.as_deref(),
+                url: props.url.as_deref(),
+                address: props.address.as_deref(),
+                city: props.city.as_deref(),
+                state: props.state.as_deref(),
+                zip: props.postal_code.as_deref(),
+                miles_away: props.miles_away,
+            },
+            appointments,
+        }
+    }
+}
+
+/// A line for each location that has run out of appointments
+fn render_cleared(locations: &[&Properties]) -> String {
+    let mut text = String::from("No longer available:\n");
+    for props in locations {
+        text.push_str(&format!(
+            "{}-{}, {} {}\n",
+            string_or_question(&props.provider),
+            string_or_question(&props.name),
+            string_or_question(&props.city),
+            string_or_question(&props.postal_code)
+        ));
+    }
+    text
+}
+
+/// The ids of the locations that have run out of appointments as
+/// `{"cleared": [...]}`, so it can't be mistaken for a report
+fn render_cleared_json(locations: &[&Properties], args: &Args) -> String {
+    let ids: Vec<u64> = locations.iter().map(|props| props.id).collect();
+    to_json(&serde_json::json!({ "cleared": ids }), args)
+}
+
+/// Format the locations as a JSON array, or with `--locations-cache` as a
+/// `CachedReport` that leaves out the details of the locations in `described`
+fn render_json(locations: &[&Properties], args: &Args, described: &mut HashSet<u64>) -> String {
+    let locations = locations
+        .iter()
+        .map(|props| JsonLocation::new(props, args.appointments_sort));
+    if !args.locations_cache {
+        return to_json(&locations.collect::<Vec<_>>(), args);
+    }
+    let mut report = CachedReport {
+        locations: BTreeMap::new(),
+        appointments: Vec::new(),
+    };
+    for location in locations {
+        if described.insert(location.id) {
+            report.locations.insert(location.id, location.details);
+        }
+        report.appointments.push(CachedAppointments {
+            id: location.id,
+            appointments: location.appointments,
+        });
+    }
+    to_json(&report, args)
+}
+
+/// `value` on a single line, or indented with `--json-pretty`
+fn to_json(value: &impl Serialize, args: &Args) -> String {
+    // serializing borrowed strings and timestamps can't fail
+    let mut ret = if args.json_pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+    .unwrap_or_default();
+    ret.push('\n');
+    ret
+}
+
+/// How long to give a webhook to accept a report before giving up on it
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POST `text` to a Slack style incoming webhook as `{"text": "..."}`
+async fn webhook_text(client: &reqwest::Client, url: &str, text: &str) -> R<()> {
+    let res = client
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .json(&serde_json::json!({ "text": text }))
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(format!("webhook returned {}", res.status()).into());
+    }
+    Ok(())
+}
+
+/// Color is only worth it for a person reading a terminal, see
+/// https://no-color.org for `NO_COLOR`
+fn use_color(args: &Args) -> bool {
+    use std::io::IsTerminal;
+    !args.no_color
+        && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && std::io::stdout().is_terminal()
+}
+
+/// Format the locations into the report used by stdout, the output file and emails
+fn render_report(locations: &[&Properties], args: &Args, color: bool) -> String {
+    let mut body = format!(
+        "{}\nReport as of {}\n{}\n\n",
+        "=".repeat(10),
+        chrono::Local::now(),
+        "=".repeat(10),
+    );
+    for props in locations {
+        body.push_str(&format!(
+            "{}\n{}",
+            "+".repeat(10),
+            props.display(args.appointments_sort).colored(color)
+        ));
+        #[cfg(feature = "qr")]
+        if let (Some(dir), Some(_)) = (&args.qr, &props.url) {
+            body.push_str(&format!(
+                "QR code: {}\n",
+                qr::code_path(dir, props).display()
+            ));
         }
+        body.push_str(&format!("\n{}\n", "+".repeat(10)));
     }
+    body
 }
 
 fn contains_new_appts(new: &[Appointment], old: &[Appointment]) -> bool {
@@ -147,156 +2561,289 @@ fn contains_new_appts(new: &[Appointment], old: &[Appointment]) -> bool {
     false
 }
 
-fn print_location(props: &Properties) {
-    println!("{}", "+".repeat(10));
-    println!("{}", props);
-    println!("{}", "+".repeat(10));
+fn count_new_appts(props: &Properties, current_info: &HashMap<u64, Vec<Appointment>>) -> usize {
+    let appointments = props.appointments.as_deref().unwrap_or_default();
+    match current_info.get(&props.id) {
+        Some(old) => appointments
+            .iter()
+            .filter(|appt| !old.contains(appt))
+            .count(),
+        None => appointments.len(),
+    }
 }
 
 #[cfg(feature = "email-notifications")]
 fn email_locations(
-    locations: &[Feature],
-    current_info: &HashMap<u64, Vec<Appointment>>,
-    zips: &[String],
+    locations: &[&Properties],
+    args: &Args,
     from_email: &str,
     to_email: &str,
+    envelope_from: Option<&str>,
+    subject: &str,
 ) -> R<()> {
-    use lettre::{Message, SmtpTransport, Transport};
-    let mut body = format!(
-        "{}\nReport as of {}\n{}\n\n",
-        "=".repeat(10),
-        chrono::Local::now(),
-        "=".repeat(10),
-    );
-    let mut send = false;
-    for entry in locations {
-        if let Some(appointments) = &entry.properties.appointments {
-            if let Some(info) = current_info.get(&entry.properties.id) {
-                if contains_new_appts(appointments, info) {
-                    if let Some(zip) = &entry.properties.postal_code {
-                        if zips.is_empty() || zips.contains(zip) {
-                            send = true;
-                            body.push_str(&format!(
-                                "{}\n{}\n{}\n",
-                                "+".repeat(10),
-                                &entry.properties,
-                                "+".repeat(10)
-                            ))
-                        }
-                    }
-                }
-            } else if !appointments.is_empty() {
-                if let Some(zip) = &entry.properties.postal_code {
-                    if zips.is_empty() || zips.contains(zip) {
-                        send = true;
-                        body.push_str(&format!(
-                            "{}\n{}\n{}\n",
-                            "+".repeat(10),
-                            &entry.properties,
-                            "+".repeat(10)
-                        ))
-                    }
-                }
-            }
-        }
-    }
-    if !send {
-        return Ok(());
-    }
-    let email = Message::builder()
-        .to(from_email.parse()?)
-        .to(to_email.parse()?)
-        .subject("New Vaccine Appointments")
-        .body(body)?;
+    let body = render_report(locations, args, false);
+    email_text(args, from_email, to_email, envelope_from, subject, body)
+}
+
+#[cfg(feature = "email-notifications")]
+fn email_text(
+    args: &Args,
+    from_email: &str,
+    to_email: &str,
+    envelope_from: Option<&str>,
+    subject: &str,
+    body: String,
+) -> R<()> {
+    use lettre::Transport;
+    let email = build_message(from_email, to_email, envelope_from, subject, body)?;
 
-    // Open a local connection on port 25
-    let mailer = SmtpTransport::unencrypted_localhost();
+    let mailer = smtp_transport(args)?;
     // Send the email
     mailer.send(&email)?;
 
     Ok(())
 }
 
-fn fetch_considered_zips(path: &Option<PathBuf>) -> Vec<String> {
-    if let Some(path) = path {
-        let s = match std::fs::read_to_string(path) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("failed to read zips.json to string: {}", e);
-                return Vec::new();
-            }
-        };
-        serde_json::from_str(&s).unwrap_or_default()
-    } else {
-        Vec::new()
+/// The email `email_text` sends, with `envelope_from` as the SMTP envelope
+/// sender instead of `from_email` when it's provided
+#[cfg(feature = "email-notifications")]
+fn build_message(
+    from_email: &str,
+    to_email: &str,
+    envelope_from: Option<&str>,
+    subject: &str,
+    body: String,
+) -> R<lettre::Message> {
+    use lettre::{address::Envelope, Message};
+    let mut builder = Message::builder();
+    if let Some(envelope_from) = envelope_from {
+        builder = builder.envelope(Envelope::new(
+            Some(envelope_from.parse()?),
+            vec![to_email.parse()?],
+        )?);
     }
+    Ok(builder
+        .from(from_email.parse()?)
+        .to(to_email.parse()?)
+        .subject(subject)
+        .body(body)?)
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct Response {
-    features: Vec<Feature>,
+/// Connect to `--smtp-host` if provided, otherwise to port 25 on this machine
+#[cfg(feature = "email-notifications")]
+fn smtp_transport(args: &Args) -> R<lettre::SmtpTransport> {
+    use lettre::{transport::smtp::authentication::Credentials, SmtpTransport};
+    const SUBMISSIONS_PORT: u16 = 465;
+    let host = match &args.smtp_host {
+        Some(host) => host,
+        None => return Ok(SmtpTransport::unencrypted_localhost()),
+    };
+    let port = args.smtp_port.unwrap_or(SUBMISSIONS_PORT);
+    let mut builder = if port == SUBMISSIONS_PORT {
+        SmtpTransport::relay(host)?
+    } else {
+        SmtpTransport::starttls_relay(host)?
+    }
+    .port(port);
+    if let (Some(user), Some(pass)) = (&args.smtp_user, &args.smtp_pass) {
+        builder = builder.credentials(Credentials::new(user.clone(), pass.expose().to_string()));
+    }
+    Ok(builder.build())
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct Feature {
-    properties: Properties,
+/// Read the `--zips-path` file, as json if it looks like json and one zip
+/// per line otherwise.
+///
+/// No zips means every location is considered, so a file that can't be read
+/// or doesn't list any is an error rather than silently turning the filter off
+fn fetch_considered_zips(path: Option<&Path>) -> R<Vec<String>> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(Vec::new()),
+    };
+    let s = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let is_json =
+        path.extension().is_some_and(|ext| ext == "json") || s.trim_start().starts_with('[');
+    let zips: Vec<String> = if is_json {
+        serde_json::from_str(&s)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+    } else {
+        let mut zips = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let zip = line.split('#').next().unwrap_or_default().trim();
+            if zip.is_empty() {
+                continue;
+            }
+            if zip.len() > 5 || !zip.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(format!(
+                    "{}:{}: expected a zip code, found {:?}",
+                    path.display(),
+                    i + 1,
+                    zip
+                )
+                .into());
+            }
+            zips.push(zip.to_string());
+        }
+        zips
+    };
+    if zips.is_empty() {
+        return Err(format!("{} doesn't list any zip codes", path.display()).into());
+    }
+    Ok(zips)
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct Properties {
-    id: u64,
-    url: Option<String>,
-    city: Option<String>,
-    state: Option<String>,
-    address: Option<String>,
-    name: Option<String>,
-    provider: Option<String>,
-    postal_code: Option<String>,
-    carries_vaccine: Option<bool>,
-    appointments_available: Option<bool>,
-    appointments_available_all_doses: Option<bool>,
-    appointments_available_2nd_dose_only: Option<bool>,
-    appointments: Option<Vec<Appointment>>,
+impl Properties {
+    /// Display this location with its appointments listed in `order`
+    fn display(&self, order: SortOrder) -> PropertiesDisplay<'_> {
+        PropertiesDisplay {
+            props: self,
+            order,
+            color: false,
+        }
+    }
+
+    /// Like `2.4 mi away`, see `--include-distance-in-alerts`
+    fn miles_away_label(&self) -> Option<String> {
+        self.miles_away.map(|miles| format!("{:.1} mi away", miles))
+    }
 }
 
 impl std::fmt::Display for Properties {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.display(SortOrder::Asc).fmt(f)
+    }
+}
+
+struct PropertiesDisplay<'a> {
+    props: &'a Properties,
+    order: SortOrder,
+    color: bool,
+}
+
+impl<'a> PropertiesDisplay<'a> {
+    /// Highlight the name, url and appointment times with terminal colors
+    fn colored(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    fn style(&self, style: Style) -> Style {
+        if self.color {
+            style
+        } else {
+            Style::new()
+        }
+    }
+}
+
+impl<'a> std::fmt::Display for PropertiesDisplay<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let props = self.props;
+        let title = format!(
+            "{}-{}",
+            string_or_question(&props.provider),
+            string_or_question(&props.name)
+        );
+        write!(f, "{}", title.style(self.style(Style::new().bold())))?;
+        if let Some(store) = &props.provider_location_id {
+            write!(f, " (store #{})", store)?;
+        }
+        writeln!(f)?;
         writeln!(
             f,
-            "{}-{}",
-            string_or_question(&self.provider),
-            string_or_question(&self.name)
+            "{}",
+            string_or_question(&props.url).style(self.style(Style::new().blue().underline()))
         )?;
-        writeln!(f, "{}", string_or_question(&self.url))?;
-        writeln!(f, "{}", string_or_question(&self.address))?;
+        writeln!(f, "{}", string_or_question(&props.address))?;
         writeln!(
             f,
             "{}, {} {}",
-            string_or_question(&self.city),
-            string_or_question(&self.state),
-            string_or_question(&self.postal_code)
+            string_or_question(&props.city),
+            string_or_question(&props.state),
+            string_or_question(&props.postal_code)
         )?;
-        if let Some(apts) = &self.appointments {
-            let sorted: HashMap<Date<Local>, Vec<DateTime<Local>>> = apts.iter().fold(std::collections::HashMap::new(), |mut acc, apt| {
-                acc.entry(apt.time.date())
-                    .and_modify(|v| v.push(apt.time))
-                    .or_insert(vec![apt.time]);
-                acc
-            });
-            let mut keys: Vec<Date<Local>> = sorted.keys().cloned().collect();
-            keys.sort();
+        if let Some(away) = props.miles_away_label() {
+            writeln!(f, "{}", away)?;
+        }
+        let slots = props.appointments.as_ref().map(Vec::len).unwrap_or(0);
+        let flag = |available: Option<bool>| match available {
+            Some(true) => "\u{2713}",
+            Some(false) => "\u{2717}",
+            None => "?",
+        };
+        match (
+            props.appointments_available_all_doses,
+            props.appointments_available_2nd_dose_only,
+        ) {
+            (None, None) => write!(f, "Doses: unknown")?,
+            (all, second) => write!(f, "Doses: all {} / 2nd-only {}", flag(all), flag(second))?,
+        }
+        writeln!(
+            f,
+            ", {} open slot{}",
+            slots,
+            if slots == 1 { "" } else { "s" }
+        )?;
+        if let Some(apts) = &props.appointments {
+            let mut sorted: HashMap<NaiveDate, Vec<(NaiveDateTime, String)>> =
+                apts.iter().fold(HashMap::new(), |mut acc, apt| {
+                    let (time, zone) = props.local_time(apt.time);
+                    acc.entry(time.date()).or_default().push((time, zone));
+                    acc
+                });
+            for times in sorted.values_mut() {
+                self.order.sort(times);
+            }
+            let mut keys: Vec<NaiveDate> = sorted.keys().cloned().collect();
+            self.order.sort(&mut keys);
+            let time_style = self.style(Style::new().green());
             for key in keys {
                 write!(f, "{}", key.format("%m/%d/%Y: "))?;
-                for (i, time) in sorted[&key].iter().enumerate() {
+                for (i, (time, _)) in sorted[&key].iter().enumerate() {
                     if i > 0 {
                         write!(f, ", ")?;
                     }
-                    write!(f, "{}", time.format("%I:%M%P"))?;
+                    write!(f, "{}", time.format("%I:%M%P").style(time_style))?;
+                }
+                if let Some((_, zone)) = sorted[&key].last() {
+                    write!(f, " {}", zone)?;
                 }
-                writeln!(f, "")?;
+                writeln!(f)?;
             }
         }
-        writeln!(f, "")
+        writeln!(f)
+    }
+}
+
+/// The order appointments are listed in a report
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortOrder {
+    /// Soonest first
+    Asc,
+    /// Latest first
+    Desc,
+}
+
+impl SortOrder {
+    fn sort<T: Ord>(self, items: &mut [T]) {
+        match self {
+            Self::Asc => items.sort(),
+            Self::Desc => items.sort_by(|a, b| b.cmp(a)),
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "asc" => Ok(Self::Asc),
+            "desc" => Ok(Self::Desc),
+            _ => Err(format!("expected `asc` or `desc`, found {:?}", s)),
+        }
     }
 }
 
@@ -308,13 +2855,443 @@ fn string_or_question(o: &Option<String>) -> &str {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
-struct Appointment {
-    time: DateTime<Local>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A location in New Jersey with an appointment at each of `times`
+    fn location(id: u64, times: &[&str]) -> Properties {
+        Properties {
+            id,
+            time_zone: Some("America/New_York".to_string()),
+            appointments: Some(times.iter().map(|time| appointment(time)).collect()),
+            ..simulated_location()
+        }
+    }
+
+    fn appointment(time: &str) -> Appointment {
+        Appointment {
+            time: DateTime::parse_from_rfc3339(time)
+                .unwrap()
+                .with_timezone(&Local),
+            kind: None,
+            vaccine_types: Vec::new(),
+            appointment_types: Vec::new(),
+        }
+    }
+
+    /// The line listing the appointments on `date`
+    fn times_line(rendered: &str, date: &str) -> String {
+        rendered
+            .lines()
+            .find(|line| line.starts_with(date))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn display_sorts_same_day_times() {
+        let props = location(
+            1,
+            &[
+                "2021-04-01T15:00:00-04:00",
+                "2021-04-01T09:30:00-04:00",
+                "2021-04-01T12:00:00-04:00",
+            ],
+        );
+        assert_eq!(
+            times_line(&props.display(SortOrder::Asc).to_string(), "04/01/2021"),
+            "04/01/2021: 09:30am, 12:00pm, 03:00pm EDT"
+        );
+        assert_eq!(
+            times_line(&props.display(SortOrder::Desc).to_string(), "04/01/2021"),
+            "04/01/2021: 03:00pm, 12:00pm, 09:30am EDT"
+        );
+    }
+
+    fn args(extra: &[&str]) -> Args {
+        let mut args = vec!["vaccine_spotter", "--state", "NJ"];
+        args.extend(extra);
+        Args::from_iter(args)
+    }
+
+    /// Options that read `tests/fixtures/{state}.json` instead of the api
+    fn fixture_args(extra: &[&str]) -> Args {
+        let base = format!("file://{}/tests/fixtures", env!("CARGO_MANIFEST_DIR"));
+        let mut all = vec!["--api-base", &base, "--state-path-template", "{state}.json"];
+        all.extend(extra);
+        args(&all)
+    }
+
+    /// Run a cycle of NJ that every location is in the area for
+    async fn poll_fixture(poll_state: &mut PollState, args: &Args) -> (Reported, Vec<Properties>) {
+        let area = AreaFilter {
+            zips: Vec::new(),
+            regions: Vec::new(),
+            radii: Vec::new(),
+        };
+        let client = build_client(args).unwrap();
+        match run_cycle(&["NJ".to_string()], poll_state, &client, &area, args).await {
+            CycleOutcome::Polled {
+                reported,
+                available,
+                failed,
+            } => {
+                assert!(failed.is_empty());
+                (reported, available)
+            }
+            CycleOutcome::FetchFailed(failed) => panic!("{}", joined_errors(&failed)),
+            CycleOutcome::Panicked(msg) => panic!("{}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn cycle_reports_new_fixture_appointments_once() {
+        let args = fixture_args(&[]);
+        let mut poll_state = PollState::default();
+        let (reported, available) = poll_fixture(&mut poll_state, &args).await;
+        let mut ids = reported.location_ids.clone();
+        ids.sort_unstable();
+        assert_eq!(ids, [7085434, 7312114]);
+        assert_eq!(reported.alerts, 1);
+        assert_eq!(reported.appointments, 3);
+        assert_eq!(available.len(), 2);
+        assert_eq!(poll_state.current_info[&7085434].len(), 2);
+        // the same appointments again are nothing new
+        let (reported, available) = poll_fixture(&mut poll_state, &args).await;
+        assert!(reported.location_ids.is_empty());
+        assert_eq!(reported.alerts, 0);
+        assert_eq!(available.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn show_all_prints_every_poll() {
+        let args = fixture_args(&["--show-all"]);
+        let mut poll_state = PollState::default();
+        for _ in 0..2 {
+            let (reported, _) = poll_fixture(&mut poll_state, &args).await;
+            assert_eq!(reported.alerts, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn url_dedupe_spans_cycles() {
+        let args = args(&["--dedupe-by", "url"]);
+        let client = build_client(&args).unwrap();
+        let area = AreaFilter {
+            zips: Vec::new(),
+            regions: Vec::new(),
+            radii: Vec::new(),
+        };
+        let feature = |id| Feature {
+            properties: location(id, &["2099-01-01T09:00:00-05:00"]),
+            geometry: None,
+        };
+        let mut poll_state = PollState::default();
+        // 2 has the same url but is listed by another state's poll, and 1
+        // can still be reported again for its own url
+        for (id, reported) in [(1, true), (2, false), (1, true)] {
+            let locations = [feature(id)];
+            let ids = report_locations(&locations, 1, None, &mut poll_state, &area, &client, &args)
+                .await
+                .location_ids;
+            assert_eq!(ids.contains(&id), reported, "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn base_url_runs_the_pipeline_against_a_file() {
+        let base = format!("file://{}/tests/fixtures", env!("CARGO_MANIFEST_DIR"));
+        let args = args(&[
+            "--base-url",
+            &base,
+            "--state-path-template",
+            "{state}.json",
+            "--provider",
+            "cvs",
+            "--once",
+        ]);
+        assert_eq!(args.state_url("nj"), format!("{}/NJ.json", base));
+        let mut poll_state = PollState::default();
+        let (reported, _) = poll_fixture(&mut poll_state, &args).await;
+        assert_eq!(reported.location_ids, [7085434]);
+        assert_eq!(reported.alerts, 1);
+        assert_eq!(reported.counts.received, 3);
+        assert_eq!(reported.counts.locations, 1);
+    }
+
+    #[test]
+    fn min_appointments_counts_new_slots() {
+        let props = location(
+            1,
+            &[
+                "2021-04-01T09:30:00-04:00",
+                "2021-04-01T10:00:00-04:00",
+                "2021-04-01T10:30:00-04:00",
+            ],
+        );
+        // the first slot was there last poll, leaving 2 new ones
+        let current_info = HashMap::from([(1, vec![appointment("2021-04-01T09:30:00-04:00")])]);
+        let just_reaches = args(&["--min-appointments", "2"]);
+        assert!(meets_min_appointments(&props, &current_info, &just_reaches));
+        let just_below = args(&["--min-appointments", "3"]);
+        assert!(!meets_min_appointments(&props, &current_info, &just_below));
+        // every slot is new for a location missing from the last poll
+        assert!(meets_min_appointments(&props, &HashMap::new(), &just_below));
+    }
+
+    #[test]
+    fn min_appointments_counts_every_slot_with_show_all() {
+        let props = location(
+            1,
+            &["2021-04-01T09:30:00-04:00", "2021-04-01T10:00:00-04:00"],
+        );
+        let current_info = HashMap::from([(1, props.appointments.clone().unwrap())]);
+        let just_reaches = args(&["--show-all", "--min-appointments", "2"]);
+        assert!(meets_min_appointments(&props, &current_info, &just_reaches));
+        let just_below = args(&["--show-all", "--min-appointments", "3"]);
+        assert!(!meets_min_appointments(&props, &current_info, &just_below));
+    }
+
+    #[test]
+    fn same_time_for_another_vaccine_is_new() {
+        let pfizer = Appointment {
+            kind: Some("Pfizer".to_string()),
+            ..appointment("2021-04-01T09:30:00-04:00")
+        };
+        let moderna = Appointment {
+            kind: Some("Moderna".to_string()),
+            ..pfizer.clone()
+        };
+        let both = vec![pfizer.clone(), moderna];
+        assert!(contains_new_appts(&both, &both[..1]));
+        assert!(!contains_new_appts(&both[..1], &both));
+        let props = Properties {
+            appointments: Some(both),
+            ..location(1, &[])
+        };
+        let current_info = HashMap::from([(1, vec![pfizer])]);
+        assert_eq!(count_new_appts(&props, &current_info), 1);
+    }
+
+    #[test]
+    fn same_time_for_other_doses_is_new() {
+        let all_doses = Appointment {
+            appointment_types: vec!["all_doses".to_string()],
+            ..appointment("2021-04-01T09:30:00-04:00")
+        };
+        let second_dose = Appointment {
+            appointment_types: vec!["2nd_dose_only".to_string()],
+            ..all_doses.clone()
+        };
+        let both = vec![all_doses, second_dose];
+        assert!(contains_new_appts(&both[1..], &both[..1]));
+        let props = Properties {
+            appointments: Some(both.clone()),
+            ..location(1, &[])
+        };
+        assert_eq!(count_new_appts(&props, &HashMap::new()), 2);
+        let current_info = HashMap::from([(1, both)]);
+        assert_eq!(count_new_appts(&props, &current_info), 0);
+    }
+
+    #[test]
+    fn locations_cache_describes_each_location_once() {
+        let args = args(&["--format", "json", "--locations-cache"]);
+        let props = location(7, &["2021-04-01T09:30:00-04:00"]);
+        let mut described = HashSet::new();
+        let first: serde_json::Value =
+            serde_json::from_str(&render_json(&[&props], &args, &mut described)).unwrap();
+        assert_eq!(first["locations"]["7"]["city"], "Nowhere");
+        assert_eq!(first["appointments"][0]["id"], 7);
+        let second: serde_json::Value =
+            serde_json::from_str(&render_json(&[&props], &args, &mut described)).unwrap();
+        assert_eq!(second["locations"], serde_json::json!({}));
+        assert_eq!(
+            second["appointments"][0]["appointments"],
+            first["appointments"][0]["appointments"]
+        );
+    }
+
+    #[test]
+    fn channel_cooldown_throttles_only_its_channel() {
+        let args = args(&["--notify-cooldown-per-channel", "stdout=15,webhook=0"]);
+        let mut last_sent = HashMap::new();
+        assert!(!throttled(Channel::Stdout, &last_sent, &args));
+        last_sent.insert(Channel::Stdout, Instant::now());
+        last_sent.insert(Channel::Webhook, Instant::now());
+        last_sent.insert(Channel::Email, Instant::now());
+        assert!(throttled(Channel::Stdout, &last_sent, &args));
+        assert!(!throttled(Channel::Webhook, &last_sent, &args));
+        assert!(!throttled(Channel::Email, &last_sent, &args));
+        assert!("pigeon=5".parse::<ChannelCooldown>().is_err());
+        assert!("email".parse::<ChannelCooldown>().is_err());
+    }
+
+    #[test]
+    fn schedule_backs_off_each_state_on_its_own() {
+        let states = vec!["NJ".to_string(), "PA".to_string()];
+        let interval: Intervals = "10".parse().unwrap();
+        let mut schedule = Schedule::new(&states);
+        schedule.failed("NJ", None, &interval);
+        schedule.failed("NJ", None, &interval);
+        schedule.failed("PA", None, &interval);
+        let failures = |schedule: &Schedule| -> Vec<u32> {
+            schedule.next.iter().map(|due| due.failures).collect()
+        };
+        assert_eq!(failures(&schedule), [2, 1]);
+        schedule.polled(&states[1..], &interval);
+        assert_eq!(failures(&schedule), [2, 0]);
+    }
+
+    #[test]
+    fn schedule_clamps_huge_waits() {
+        let states = vec!["NJ".to_string()];
+        let interval: Intervals = u64::MAX.to_string().parse().unwrap();
+        let mut schedule = Schedule::new(&states);
+        schedule.polled(&states, &interval);
+        schedule.failed("NJ", None, &interval);
+        schedule.failed("NJ", Some(Duration::MAX), &interval);
+        assert!(schedule.wait() <= MAX_WAIT);
+    }
+
+    #[test]
+    fn zip_prefix_matches_every_zip_starting_with_it() {
+        let filters = vec!["086".to_string(), "07030".to_string()];
+        assert!(zip_matches("08619", &filters));
+        assert!(zip_matches("07030", &filters));
+        assert!(!zip_matches("07031", &filters));
+        assert!(!zip_matches("10086", &filters));
+    }
+
+    #[test]
+    fn zip_plus_four_matches_its_zip() {
+        let filters = vec!["08619".to_string()];
+        assert!(zip_matches("08619-1234", &filters));
+        assert!(!zip_matches("08618-1234", &filters));
+        assert!(zip_matches("08619-1234", &["086".to_string()]));
+    }
+
+    #[test]
+    fn location_without_zip_is_outside_zip_filter() {
+        let area = AreaFilter {
+            zips: vec!["086".to_string()],
+            regions: Vec::new(),
+            radii: Vec::new(),
+        };
+        let feature = |postal_code: Option<&str>| Feature {
+            properties: Properties {
+                postal_code: postal_code.map(String::from),
+                ..location(1, &[])
+            },
+            geometry: None,
+        };
+        assert!(area.matches(&feature(Some("08619"))));
+        assert!(!area.matches(&feature(None)));
+    }
+
+    #[test]
+    fn window_includes_after_and_excludes_before() {
+        let at = |s: &str| time_of_day(s).unwrap();
+        let (after, before) = (Some(at("09:00")), Some(at("17:00")));
+        assert!(in_window(at("09:00"), after, before));
+        assert!(!in_window(at("08:59"), after, before));
+        assert!(in_window(at("16:59"), after, before));
+        assert!(!in_window(at("17:00"), after, before));
+        assert!(in_window(at("00:00"), None, before));
+        assert!(in_window(at("23:59"), after, None));
+        assert!(in_window(at("03:00"), None, None));
+    }
+
+    #[test]
+    fn window_wraps_past_midnight() {
+        let at = |s: &str| time_of_day(s).unwrap();
+        let (after, before) = (Some(at("22:00")), Some(at("06:00")));
+        assert!(in_window(at("22:00"), after, before));
+        assert!(in_window(at("23:30"), after, before));
+        assert!(in_window(at("00:00"), after, before));
+        assert!(in_window(at("05:59"), after, before));
+        assert!(!in_window(at("06:00"), after, before));
+        assert!(!in_window(at("21:59"), after, before));
+        assert!(!in_window(at("12:00"), after, before));
+    }
+
+    #[test]
+    fn provider_filter_ignores_case_and_matches_part_of_a_name() {
+        let cvs = vec!["CVS".to_string()];
+        let rite = vec!["rite".to_string()];
+        assert!(provider_matches(Some("cvs"), &cvs, &[]));
+        assert!(provider_matches(Some("Rite_Aid"), &rite, &[]));
+        assert!(!provider_matches(Some("walgreens"), &cvs, &[]));
+        assert!(!provider_matches(Some("RITE_AID"), &[], &rite));
+        assert!(!provider_matches(Some("cvs"), &cvs, &cvs));
+        assert!(provider_matches(None, &[], &cvs));
+        assert!(!provider_matches(None, &cvs, &[]));
+    }
+
+    #[test]
+    fn intervals_parse_default_and_per_state() {
+        let intervals: Intervals = "30,nj=120, NY = 90".parse().unwrap();
+        assert_eq!(intervals.for_state("NJ"), Duration::from_secs(120));
+        assert_eq!(intervals.for_state("ny"), Duration::from_secs(90));
+        assert_eq!(intervals.for_state("PA"), Duration::from_secs(30));
+        let only_state: Intervals = "NJ=120".parse().unwrap();
+        assert_eq!(only_state.for_state("PA"), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn intervals_reject_bad_values() {
+        assert!("ZZ=5".parse::<Intervals>().is_err());
+        assert!("0".parse::<Intervals>().is_err());
+        assert!("NJ=0".parse::<Intervals>().is_err());
+        assert!("NJ=soon".parse::<Intervals>().is_err());
+    }
+
+    #[cfg(feature = "email-notifications")]
+    #[test]
+    fn message_has_from_and_to_headers() {
+        use lettre::message::header;
+        let message = build_message(
+            "alerts@example.com",
+            "me@example.com",
+            None,
+            "Vaccine Appointments",
+            "body".to_string(),
+        )
+        .unwrap();
+        let headers = message.headers();
+        assert_eq!(
+            headers.get::<header::From>().unwrap().0.to_string(),
+            "alerts@example.com"
+        );
+        assert_eq!(
+            headers.get::<header::To>().unwrap().0.to_string(),
+            "me@example.com"
+        );
+        let envelope = message.envelope();
+        assert_eq!(envelope.from().unwrap().to_string(), "alerts@example.com");
+        assert_eq!(envelope.to()[0].to_string(), "me@example.com");
+    }
 
-impl PartialEq<DateTime<Local>> for Appointment {
-    fn eq(&self, other: &DateTime<Local>) -> bool {
-        self.time == *other
+    #[cfg(feature = "email-notifications")]
+    #[test]
+    fn message_uses_envelope_from() {
+        use lettre::message::header;
+        let message = build_message(
+            "alerts@example.com",
+            "me@example.com",
+            Some("relay-user@example.com"),
+            "Vaccine Appointments",
+            "body".to_string(),
+        )
+        .unwrap();
+        let from = message.headers().get::<header::From>().unwrap();
+        assert_eq!(from.0.to_string(), "alerts@example.com");
+        let envelope = message.envelope();
+        assert_eq!(
+            envelope.from().unwrap().to_string(),
+            "relay-user@example.com"
+        );
+        assert_eq!(envelope.to()[0].to_string(), "me@example.com");
     }
 }
@@ -0,0 +1,142 @@
+//! Prometheus metrics for long running deployments, see `--metrics-port`
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::R;
+
+/// The values served from `/metrics`, updated by the poll loop
+#[derive(Debug, Default)]
+pub struct Metrics {
+    polls: AtomicU64,
+    poll_errors: AtomicU64,
+    locations_with_availability: AtomicU64,
+    last_poll_timestamp: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a poll that fetched and processed appointments, apart from
+    /// `failed_states` that couldn't be fetched
+    pub fn polled(&self, locations_with_availability: usize, failed_states: usize) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_errors
+            .fetch_add(failed_states as u64, Ordering::Relaxed);
+        self.locations_with_availability
+            .store(locations_with_availability as u64, Ordering::Relaxed);
+        self.last_poll_timestamp
+            .store(chrono::Utc::now().timestamp() as u64, Ordering::Relaxed);
+    }
+
+    /// Count a poll that failed to fetch or process appointments, with
+    /// `errors` for each state it couldn't fetch
+    pub fn failed(&self, errors: usize) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_errors.fetch_add(errors as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n",
+                value.load(Ordering::Relaxed),
+                name = name,
+                kind = kind,
+                help = help,
+            ));
+        };
+        metric(
+            "vaccinespotter_polls_total",
+            "counter",
+            "Polls of the vaccinespotter api",
+            &self.polls,
+        );
+        metric(
+            "vaccinespotter_poll_errors_total",
+            "counter",
+            "Failed polls, counting each state that couldn't be fetched",
+            &self.poll_errors,
+        );
+        metric(
+            "vaccinespotter_locations_with_availability",
+            "gauge",
+            "Locations in the target area with appointments as of the last successful poll",
+            &self.locations_with_availability,
+        );
+        metric(
+            "vaccinespotter_last_poll_timestamp",
+            "gauge",
+            "Unix time of the last successful poll",
+            &self.last_poll_timestamp,
+        );
+        out
+    }
+}
+
+/// A running metrics server, `stop` waits for it to finish any open requests
+pub struct MetricsServer {
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(());
+        if let Err(e) = self.task.await {
+            log::error!("metrics server failed to stop: {}", e);
+        }
+    }
+}
+
+/// Start serving `metrics` on `port` of every interface, failing right away if
+/// the port can't be bound
+pub fn serve(port: u16, metrics: Arc<Metrics>) -> R<MetricsServer> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&req, &metrics)) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    log::info!("serving metrics on http://{}/metrics", addr);
+    let (shutdown, rx) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let server = server.with_graceful_shutdown(async {
+            let _ = rx.await;
+        });
+        if let Err(e) = server.await {
+            log::error!("metrics server failed: {}", e);
+        }
+    });
+    Ok(MetricsServer { shutdown, task })
+}
+
+fn respond(req: &Request<Body>, metrics: &Metrics) -> Response<Body> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut res = Response::new(Body::from("not found\n"));
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return res;
+    }
+    let mut res = Response::new(Body::from(metrics.render()));
+    res.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    res
+}
@@ -0,0 +1,114 @@
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::Local;
+
+/// When the `--output` file should be rolled over to a new file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotate {
+    /// Write each day's reports to a file with the date in its name
+    Daily,
+    /// Move the current file aside once it reaches this many bytes
+    Size(u64),
+}
+
+impl FromStr for Rotate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        if s == "daily" {
+            return Ok(Self::Daily);
+        }
+        let (digits, multiplier) = if let Some(n) = s.strip_suffix("gb") {
+            (n, 1024 * 1024 * 1024)
+        } else if let Some(n) = s.strip_suffix("mb") {
+            (n, 1024 * 1024)
+        } else if let Some(n) = s.strip_suffix("kb") {
+            (n, 1024)
+        } else {
+            (s.as_str(), 1)
+        };
+        let size: u64 = digits
+            .trim()
+            .parse()
+            .map_err(|_| format!("expected `daily` or a size like `10MB`, found {:?}", s))?;
+        if size == 0 {
+            return Err("rotation size must be greater than 0".to_string());
+        }
+        size.checked_mul(multiplier)
+            .map(Self::Size)
+            .ok_or_else(|| format!("rotation size {:?} is too large", s))
+    }
+}
+
+/// Append `contents` to the file at `path`, rotating it first if needed.
+///
+/// `path` may contain strftime style placeholders, e.g. `reports/%Y%m%d/%H%M%S.txt`,
+/// any missing parent directories are created
+pub fn append(path: &Path, rotate: Option<Rotate>, contents: &str) -> std::io::Result<()> {
+    let path = &expand_template(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let path = match rotate {
+        Some(Rotate::Daily) => with_suffix(path, &Local::now().format("%Y-%m-%d").to_string()),
+        Some(Rotate::Size(max)) => {
+            let len = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            if len > 0 && len + contents.len() as u64 > max {
+                let rotated =
+                    with_suffix(path, &Local::now().format("%Y-%m-%d-%H%M%S").to_string());
+                log::info!("rotating {} to {}", path.display(), rotated.display());
+                std::fs::rename(path, rotated)?;
+            }
+            path.to_path_buf()
+        }
+        None => path.to_path_buf(),
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+/// Replace the file at `path` with `contents` by writing them to a temporary
+/// file beside it and renaming that over the original, so `path` never ends
+/// up half written
+pub fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Fill in any strftime style placeholders in `path` with the current time
+fn expand_template(path: &Path) -> PathBuf {
+    let template = path.to_string_lossy();
+    if !template.contains('%') {
+        return path.to_path_buf();
+    }
+    let mut expanded = String::new();
+    if write!(expanded, "{}", Local::now().format(&template)).is_err() {
+        log::warn!("invalid placeholder in {}, using it as is", template);
+        return path.to_path_buf();
+    }
+    PathBuf::from(expanded)
+}
+
+/// Insert `suffix` between the file stem and extension,
+/// `appointments.json` becomes `appointments-<suffix>.json`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
@@ -0,0 +1,27 @@
+//! QR codes of the booking urls, enabled with the `qr` feature
+
+use std::path::{Path, PathBuf};
+
+use image::Luma;
+use qrcode::QrCode;
+
+use crate::{Properties, R};
+
+/// Where the QR code for `props` is written in `dir`
+pub fn code_path(dir: &Path, props: &Properties) -> PathBuf {
+    dir.join(format!("{}.png", props.id))
+}
+
+/// Write a PNG QR code of the booking url for each location that has one
+pub fn write_codes(dir: &Path, locations: &[&Properties]) -> R<()> {
+    std::fs::create_dir_all(dir)?;
+    for props in locations {
+        if let Some(url) = &props.url {
+            QrCode::new(url)?
+                .render::<Luma<u8>>()
+                .build()
+                .save(code_path(dir, props))?;
+        }
+    }
+    Ok(())
+}
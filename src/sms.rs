@@ -0,0 +1,157 @@
+//! Text message alerts through Twilio, enabled with the `sms-notifications` feature
+
+use std::time::Duration;
+
+use crate::{string_or_question, Properties, R};
+
+/// Two SMS segments, anything longer gets split into more messages which
+/// cost more and can arrive out of order
+const MAX_LEN: usize = 320;
+/// Room kept at the end of a message for the `+N more` line
+const MORE_LEN: usize = 12;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Text a short summary of the locations to `to` from the Twilio number `from`
+pub async fn sms_locations(
+    locations: &[&Properties],
+    client: &reqwest::Client,
+    sid: &str,
+    token: &str,
+    from: &str,
+    to: &str,
+) -> R<()> {
+    sms_text(client, sid, token, from, to, &compact_message(locations)).await
+}
+
+/// Text `body` as it is, cut off at `MAX_LEN`
+pub async fn sms_text(
+    client: &reqwest::Client,
+    sid: &str,
+    token: &str,
+    from: &str,
+    to: &str,
+    body: &str,
+) -> R<()> {
+    let url = format!(
+        "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+        sid
+    );
+    let body: String = body.chars().take(MAX_LEN).collect();
+    let res = client
+        .post(&url)
+        .basic_auth(sid, Some(token))
+        .timeout(TIMEOUT)
+        .form(&[("To", to), ("From", from), ("Body", body.as_str())])
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        let status = res.status();
+        let text = res.text().await.unwrap_or_default();
+        return Err(format!("twilio returned {}: {}", status, text).into());
+    }
+    Ok(())
+}
+
+/// One line per location for as many as fit in `MAX_LEN`, then a count of
+/// the ones left out
+fn compact_message(locations: &[&Properties]) -> String {
+    let mut body = String::new();
+    let mut included = 0;
+    for props in locations {
+        let line = location_line(props);
+        let remaining = locations.len() - included - 1;
+        let reserve = if remaining > 0 { MORE_LEN } else { 0 };
+        let len = body.chars().count() + line.chars().count() + 1;
+        if included > 0 && len + reserve > MAX_LEN {
+            break;
+        }
+        if included > 0 {
+            body.push('\n');
+        }
+        if included == 0 && len + reserve > MAX_LEN {
+            // a single location always goes out, even if it has to be cut short
+            body.extend(line.chars().take(MAX_LEN - reserve));
+        } else {
+            body.push_str(&line);
+        }
+        included += 1;
+    }
+    if included < locations.len() {
+        body.push_str(&format!("\n+{} more", locations.len() - included));
+    }
+    body
+}
+
+/// The provider, city, next appointment and booking url
+fn location_line(props: &Properties) -> String {
+    let mut line = format!(
+        "{} {}",
+        string_or_question(&props.provider),
+        string_or_question(&props.city)
+    );
+    if let Some(away) = props.miles_away_label() {
+        line.push_str(&format!(" ({})", away));
+    }
+    let next = props
+        .appointments
+        .iter()
+        .flatten()
+        .map(|appt| appt.time)
+        .min();
+    if let Some(next) = next {
+        let (time, zone) = props.local_time(next);
+        line.push_str(&format!(" {} {}", time.format("%m/%d %I:%M%P"), zone));
+    }
+    line.push(' ');
+    line.push_str(string_or_question(&props.url));
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A location without appointments whose line is exactly `len` characters
+    fn location(len: usize) -> Properties {
+        let prefix = "cvs Trenton https://cvs.example/";
+        Properties {
+            provider: Some("cvs".to_string()),
+            city: Some("Trenton".to_string()),
+            url: Some(format!(
+                "https://cvs.example/{}",
+                "a".repeat(len - prefix.len())
+            )),
+            appointments: None,
+            ..crate::simulated_location()
+        }
+    }
+
+    #[test]
+    fn single_location_at_the_limit_is_kept_whole() {
+        let props = location(MAX_LEN);
+        assert_eq!(compact_message(&[&props]), location_line(&props));
+    }
+
+    #[test]
+    fn single_location_over_the_limit_is_cut_short() {
+        let props = location(MAX_LEN + 1);
+        let body = compact_message(&[&props]);
+        assert_eq!(body.chars().count(), MAX_LEN);
+        assert!(location_line(&props).starts_with(&body));
+    }
+
+    #[test]
+    fn locations_filling_the_limit_all_fit() {
+        let (first, second) = (location(100), location(MAX_LEN - 101));
+        let body = compact_message(&[&first, &second]);
+        assert_eq!(body.chars().count(), MAX_LEN);
+        assert!(!body.contains("more"));
+    }
+
+    #[test]
+    fn location_just_over_the_limit_is_counted_instead() {
+        let (first, second) = (location(100), location(MAX_LEN - 100));
+        let body = compact_message(&[&first, &second]);
+        assert_eq!(body, format!("{}\n+1 more", location_line(&first)));
+    }
+}
@@ -0,0 +1,32 @@
+use chrono_tz::Tz;
+
+/// The timezone most of `state` observes, `state` is a 2 digit postal code
+pub fn timezone(state: &str) -> Option<Tz> {
+    use chrono_tz::{America, Pacific};
+    let tz = match state.to_uppercase().as_str() {
+        "CT" | "DC" | "DE" | "FL" | "GA" | "KY" | "MA" | "MD" | "ME" | "NC" | "NH" | "NJ"
+        | "NY" | "OH" | "PA" | "RI" | "SC" | "VA" | "VT" | "WV" => America::New_York,
+        "MI" => America::Detroit,
+        "IN" => America::Indiana::Indianapolis,
+        "AL" | "AR" | "IA" | "IL" | "KS" | "LA" | "MN" | "MO" | "MS" | "ND" | "NE" | "OK"
+        | "SD" | "TN" | "TX" | "WI" => America::Chicago,
+        "CO" | "MT" | "NM" | "UT" | "WY" => America::Denver,
+        "ID" => America::Boise,
+        "AZ" => America::Phoenix,
+        "CA" | "NV" | "OR" | "WA" => America::Los_Angeles,
+        "AK" => America::Anchorage,
+        "HI" => Pacific::Honolulu,
+        "PR" => America::Puerto_Rico,
+        "VI" => America::St_Thomas,
+        "GU" => Pacific::Guam,
+        "MP" => Pacific::Saipan,
+        "AS" => Pacific::Pago_Pago,
+        _ => return None,
+    };
+    Some(tz)
+}
+
+/// If `state` is the 2 digit postal code of a state, DC or a territory
+pub fn is_known(state: &str) -> bool {
+    timezone(state).is_some()
+}
@@ -0,0 +1,224 @@
+//! Telegram bot alerts, enabled with the `telegram-notifications` feature
+
+use std::time::Duration;
+
+use crate::{string_or_question, Properties, R};
+
+/// The longest message the Bot API will accept
+const MAX_LEN: usize = 4096;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send the locations to `chat_id` as the bot with `token`, in as few
+/// messages as fit under Telegram's length limit
+pub async fn telegram_locations(
+    locations: &[&Properties],
+    client: &reqwest::Client,
+    token: &str,
+    chat_id: &str,
+) -> R<()> {
+    for text in split_messages(locations) {
+        send_message(client, token, chat_id, &text, true).await?;
+    }
+    Ok(())
+}
+
+/// Send `text` without any formatting, split every `MAX_LEN` characters
+pub async fn telegram_text(
+    client: &reqwest::Client,
+    token: &str,
+    chat_id: &str,
+    text: &str,
+) -> R<()> {
+    let chars: Vec<char> = text.chars().collect();
+    for chunk in chars.chunks(MAX_LEN) {
+        let chunk: String = chunk.iter().collect();
+        send_message(client, token, chat_id, &chunk, false).await?;
+    }
+    Ok(())
+}
+
+async fn send_message(
+    client: &reqwest::Client,
+    token: &str,
+    chat_id: &str,
+    text: &str,
+    markdown: bool,
+) -> R<()> {
+    let url = format!("https://api.telegram.org/bot{}/sendMessage", token);
+    let mut body = serde_json::json!({
+        "chat_id": chat_id,
+        "text": text,
+        "disable_web_page_preview": true,
+    });
+    if markdown {
+        body["parse_mode"] = "Markdown".into();
+    }
+    // the url has the token in it, and so does any error sending to it
+    let res = client
+        .post(&url)
+        .timeout(TIMEOUT)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| e.to_string().replace(token, "***"))?;
+    if !res.status().is_success() {
+        let status = res.status();
+        let body = res.text().await.unwrap_or_default();
+        return Err(format!("telegram returned {}: {}", status, body).into());
+    }
+    Ok(())
+}
+
+/// Group whole locations into messages of at most `MAX_LEN` characters,
+/// a location too long for one message is split between its lines so no
+/// bold text or link is cut in two
+fn split_messages(locations: &[&Properties]) -> Vec<String> {
+    let blocks = locations.iter().flat_map(|props| {
+        let block = location_block(props);
+        if block.chars().count() <= MAX_LEN {
+            vec![block]
+        } else {
+            pack(block.lines().map(fit_line), "\n")
+        }
+    });
+    pack(blocks, "\n\n")
+}
+
+/// Join `pieces` of at most `MAX_LEN` characters with `sep` into as few
+/// strings of at most `MAX_LEN` as they fit in
+fn pack(pieces: impl Iterator<Item = String>, sep: &str) -> Vec<String> {
+    let mut packed = Vec::new();
+    let mut current = String::new();
+    for piece in pieces {
+        let len = current.chars().count() + sep.len() + piece.chars().count();
+        if !current.is_empty() && len > MAX_LEN {
+            packed.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push_str(sep);
+        }
+        current.push_str(&piece);
+    }
+    if !current.is_empty() {
+        packed.push(current);
+    }
+    packed
+}
+
+/// A line too long to send even on its own is cut short, without the
+/// formatting it can no longer close
+fn fit_line(line: &str) -> String {
+    if line.chars().count() <= MAX_LEN {
+        return line.to_string();
+    }
+    line.chars()
+        .filter(|c| !matches!(c, '_' | '*' | '`' | '[' | '\\'))
+        .take(MAX_LEN)
+        .collect()
+}
+
+/// The bold provider and name, a link to book, where it is and the next
+/// appointment
+fn location_block(props: &Properties) -> String {
+    // nothing can be escaped inside bold text or a link, so only the `*`
+    // that would end the bold early is dropped
+    let title = format!(
+        "{}-{}",
+        string_or_question(&props.provider),
+        string_or_question(&props.name)
+    );
+    let mut block = format!("*{}*\n", title.replace('*', ""));
+    if let Some(url) = &props.url {
+        block.push_str(&format!("[{}]({})\n", url.replace(']', ""), url));
+    }
+    block.push_str(&escape(&format!(
+        "{}, {}, {} {}",
+        string_or_question(&props.address),
+        string_or_question(&props.city),
+        string_or_question(&props.state),
+        string_or_question(&props.postal_code)
+    )));
+    if let Some(away) = props.miles_away_label() {
+        block.push_str(&format!("\n{}", away));
+    }
+    let appointments: Vec<_> = props.appointments.iter().flatten().collect();
+    if let Some(next) = appointments.iter().map(|appt| appt.time).min() {
+        let (time, zone) = props.local_time(next);
+        block.push_str(&format!(
+            "\n{} appointments, next {} {}",
+            appointments.len(),
+            time.format("%m/%d %I:%M%P"),
+            zone
+        ));
+    }
+    block
+}
+
+/// Keep text outside of bold and links from being read as formatting
+fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '_' | '*' | '`' | '[') {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(address: &str) -> Properties {
+        Properties {
+            provider: Some("cvs".to_string()),
+            name: Some("CVS 1".to_string()),
+            url: Some("https://cvs.example/1".to_string()),
+            address: Some(address.to_string()),
+            appointments: None,
+            ..crate::simulated_location()
+        }
+    }
+
+    #[test]
+    fn locations_are_kept_whole_across_messages() {
+        let props = location(&"1 Main St ".repeat(20));
+        let locations: Vec<&Properties> = std::iter::repeat_n(&props, 30).collect();
+        let messages = split_messages(&locations);
+        assert!(messages.len() > 1);
+        let block = location_block(&props);
+        for message in &messages {
+            assert!(message.chars().count() <= MAX_LEN);
+            assert!(message.split("\n\n").all(|b| b == block));
+        }
+        let sent: usize = messages.iter().map(|m| m.split("\n\n").count()).sum();
+        assert_eq!(sent, 30);
+    }
+
+    #[test]
+    fn location_over_the_limit_is_split_between_lines() {
+        // too long together, but each line fits
+        let props = location(&"1 Main St ".repeat(404));
+        let block = location_block(&props);
+        assert!(block.chars().count() > MAX_LEN);
+        let messages = split_messages(&[&props]);
+        assert_eq!(messages.len(), 2);
+        // the bold title and the link are left whole in the first message
+        assert_eq!(
+            messages[0],
+            "*cvs-CVS 1*\n[https://cvs.example/1](https://cvs.example/1)"
+        );
+        assert!(messages[1].starts_with("1 Main St"));
+        assert!(messages.iter().all(|m| m.chars().count() <= MAX_LEN));
+        assert_eq!(messages.join("\n"), block);
+    }
+
+    #[test]
+    fn line_over_the_limit_loses_its_formatting() {
+        let line = format!("*{}*", "a_bc".repeat(2000));
+        let fit = fit_line(&line);
+        assert_eq!(fit.chars().count(), MAX_LEN);
+        assert!(!fit.contains(['*', '_']));
+    }
+}
@@ -0,0 +1,182 @@
+//! A live dashboard of the matching locations, enabled with the `tui` feature
+
+use std::time::{Duration, Instant};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Row, Table},
+    DefaultTerminal,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::{string_or_question, Properties, R};
+
+/// What the user asked for while we were waiting on the next poll
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// The wait is over
+    Poll,
+    /// Poll every state now
+    Refresh,
+    Quit,
+}
+
+struct Location {
+    name: String,
+    city: String,
+    zip: String,
+    slots: usize,
+}
+
+pub struct Dashboard {
+    terminal: DefaultTerminal,
+    keys: UnboundedReceiver<KeyEvent>,
+    locations: Vec<Location>,
+    last_update: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Dashboard {
+    /// Take over the terminal, it is handed back when the dashboard is dropped
+    pub fn new() -> R<Self> {
+        let terminal = ratatui::try_init()?;
+        // ratatui's hook hands the terminal back on any panic, even one a poll
+        // recovers from, and the default hook prints over the dashboard. Log it
+        // instead and leave restoring the terminal to `drop` while unwinding
+        std::panic::set_hook(Box::new(|info| log::error!("{}", info)));
+        let (tx, keys) = mpsc::unbounded_channel();
+        // crossterm only offers a blocking read without its `event-stream` feature
+        std::thread::spawn(move || {
+            while let Ok(ev) = event::read() {
+                if let Event::Key(key) = ev {
+                    if key.kind == KeyEventKind::Press && tx.send(key).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        let mut ret = Self {
+            terminal,
+            keys,
+            locations: Vec::new(),
+            last_update: None,
+            last_error: None,
+        };
+        ret.draw()?;
+        Ok(ret)
+    }
+
+    /// Replace the table with the results of the latest poll
+    pub fn update(&mut self, locations: &[&Properties]) -> R<()> {
+        self.locations = locations
+            .iter()
+            .map(|props| Location {
+                name: format!(
+                    "{}-{}",
+                    string_or_question(&props.provider),
+                    string_or_question(&props.name)
+                ),
+                city: string_or_question(&props.city).to_string(),
+                zip: string_or_question(&props.postal_code).to_string(),
+                slots: props.appointments.as_ref().map(Vec::len).unwrap_or(0),
+            })
+            .collect();
+        self.last_update = Some(Instant::now());
+        self.last_error = None;
+        self.draw()
+    }
+
+    /// Note an error in the status line, keeping the last good table
+    pub fn failed(&mut self, error: String) -> R<()> {
+        self.last_error = Some(error);
+        self.draw()
+    }
+
+    /// Wait out the `interval` before the next poll, redrawing once a second
+    /// so the time since the last update stays current.
+    ///
+    /// `q` quits, `r` skips the rest of the wait and polls every state
+    pub async fn wait(&mut self, interval: Duration) -> R<Command> {
+        let deadline = tokio::time::Instant::now() + interval;
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => return Ok(Command::Poll),
+                _ = tick.tick() => self.draw()?,
+                key = self.keys.recv() => {
+                    let key = match key {
+                        Some(key) => key,
+                        None => return Ok(Command::Quit),
+                    };
+                    match key.code {
+                        KeyCode::Char('q') => return Ok(Command::Quit),
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            return Ok(Command::Quit)
+                        }
+                        KeyCode::Char('r') => return Ok(Command::Refresh),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self) -> R<()> {
+        let status = match (&self.last_error, self.last_update) {
+            (Some(e), _) => format!("last error: {}", e),
+            (None, Some(last)) => format!(
+                "{} locations with appointments, updated {}s ago",
+                self.locations.len(),
+                last.elapsed().as_secs()
+            ),
+            (None, None) => "waiting on the first poll".to_string(),
+        };
+        let rows = self.locations.iter().map(|loc| {
+            Row::new(vec![
+                loc.name.clone(),
+                loc.city.clone(),
+                loc.zip.clone(),
+                loc.slots.to_string(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Percentage(50),
+                Constraint::Percentage(25),
+                Constraint::Length(10),
+                Constraint::Length(6),
+            ],
+        )
+        .header(
+            Row::new(vec!["Location", "City", "Zip", "Slots"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Vaccine Spotter"),
+        );
+        self.terminal.draw(|frame| {
+            let [body, footer] =
+                Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+            frame.render_widget(table, body);
+            frame.render_widget(
+                Line::from(format!("{} | q: quit, r: refresh", status)),
+                footer,
+            );
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        // back to the default hook
+        drop(std::panic::take_hook());
+        ratatui::restore();
+    }
+}
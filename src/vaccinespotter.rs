@@ -0,0 +1,550 @@
+//! The vaccinespotter.org api, the shape of its responses and fetching them
+
+use std::{str::FromStr, sync::Once, time::Duration};
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// How long to wait before fetching again after a response couldn't be parsed
+const PARSE_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// How much of a response that couldn't be parsed or was an error to include
+/// in the error
+const BODY_SNIPPET_LEN: usize = 200;
+
+/// Fetch the current appointments from `url`, fetching again up to `parse_retries`
+/// times if the response comes back truncated or otherwise can't be parsed.
+///
+/// A `file://` url is read from disk, without any retries. Times without an
+/// offset at a location without a timezone are read in `assumed`
+pub async fn fetch_state(
+    client: &reqwest::Client,
+    url: &str,
+    parse_retries: u32,
+    assumed: Option<Tz>,
+) -> Result<Response, FetchError> {
+    static LOG_VERSION: Once = Once::new();
+    if let Some(path) = url.strip_prefix("file://") {
+        let body = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| FetchError::Network(format!("Failed to read {}: {}", path, e).into()))?;
+        return parse_response(&body, assumed)
+            .map_err(|e| FetchError::Parse(ParseError::new(url, None, &body, e)));
+    }
+    let mut attempt = 0;
+    loop {
+        log::debug!("requesting new appointments");
+        let res = client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| FetchError::Network(e.into()))?;
+        LOG_VERSION.call_once(|| log::info!("connected to the api over {:?}", res.version()));
+        if !res.status().is_success() {
+            return Err(FetchError::Status(StatusError::new(url, res).await));
+        }
+        let status = res.status();
+        // a body cut off partway is as worth retrying as one that doesn't parse
+        let parsed = match res.text().await {
+            Ok(body) => parse_response(&body, assumed)
+                .map_err(|e| FetchError::Parse(ParseError::new(url, Some(status), &body, e))),
+            Err(e) => Err(FetchError::Network(e.into())),
+        };
+        match parsed {
+            Ok(res) => return Ok(res),
+            Err(e) if attempt < parse_retries => {
+                attempt += 1;
+                log::warn!("{}, retrying ({}/{})", e, attempt, parse_retries);
+                tokio::time::sleep(PARSE_RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Why the appointments for a state couldn't be fetched
+#[derive(Debug)]
+pub enum FetchError {
+    /// The request couldn't be sent, or the response or file couldn't be read
+    Network(Box<dyn std::error::Error + Send + Sync>),
+    /// The api answered with something other than a 2xx
+    Status(StatusError),
+    /// The response wasn't the json we expected
+    Parse(ParseError),
+}
+
+impl FetchError {
+    /// How long the api asked us to wait before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Status(e) => e.retry_after,
+            _ => None,
+        }
+    }
+
+    /// If sending the same request again won't help, see
+    /// `StatusError::is_client_error`
+    pub fn is_client_error(&self) -> bool {
+        matches!(self, Self::Status(e) if e.is_client_error())
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Network(e) => e.fmt(f),
+            Self::Status(e) => e.fmt(f),
+            Self::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// A response that couldn't be parsed, with enough of it to tell why, e.g.
+/// an html error page or an empty body
+#[derive(Debug)]
+pub struct ParseError {
+    url: String,
+    status: Option<reqwest::StatusCode>,
+    error: serde_json::Error,
+    /// The start of the body
+    body: String,
+}
+
+impl ParseError {
+    fn new(
+        url: &str,
+        status: Option<reqwest::StatusCode>,
+        body: &str,
+        error: serde_json::Error,
+    ) -> Self {
+        Self {
+            url: url.to_string(),
+            status,
+            error,
+            body: body.chars().take(BODY_SNIPPET_LEN).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Failed to parse {}", self.url)?;
+        if let Some(status) = self.status {
+            write!(f, " ({})", status)?;
+        }
+        write!(f, ": {}, ", self.error)?;
+        let body = self.body.trim_start();
+        if body.is_empty() {
+            write!(f, "the response was empty")
+        } else if body.starts_with('<') {
+            write!(f, "got html instead of json: {:?}", self.body)
+        } else {
+            write!(f, "the response started with {:?}", self.body)
+        }
+    }
+}
+
+/// The api answered with something other than a 2xx
+#[derive(Debug)]
+pub struct StatusError {
+    url: String,
+    status: reqwest::StatusCode,
+    /// How long the api asked us to wait before trying again
+    pub retry_after: Option<Duration>,
+    /// The start of the body, an error page can say more than its status
+    body: String,
+}
+
+impl StatusError {
+    /// If the request itself was wrong, e.g. a 404 for a state the api
+    /// doesn't have, so sending it again won't help
+    pub fn is_client_error(&self) -> bool {
+        self.status.is_client_error() && self.status != reqwest::StatusCode::TOO_MANY_REQUESTS
+    }
+
+    async fn new(url: &str, res: reqwest::Response) -> Self {
+        let retry_after = res
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        let status = res.status();
+        let body = res.text().await.unwrap_or_default();
+        Self {
+            url: url.to_string(),
+            status,
+            retry_after,
+            body: body.chars().take(BODY_SNIPPET_LEN).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} returned {}", self.url, self.status)?;
+        let body = self.body.trim();
+        if !body.is_empty() {
+            write!(f, ": {:?}", body)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let until = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (until.with_timezone(&Local) - Local::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Response {
+    pub features: Vec<Feature>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Feature {
+    pub properties: Properties,
+    #[serde(default)]
+    pub geometry: Option<Geometry>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Geometry {
+    /// GeoJSON order, `[lon, lat]`
+    pub coordinates: [f64; 2],
+}
+
+impl Geometry {
+    pub fn coordinate(&self) -> Coordinate {
+        Coordinate {
+            lat: self.coordinates[1],
+            lon: self.coordinates[0],
+        }
+    }
+}
+
+/// A point on the map, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinate {
+    lat: f64,
+    lon: f64,
+}
+
+impl Coordinate {
+    /// The great circle distance to `other` by the haversine formula
+    pub fn miles_to(self, other: Coordinate) -> f64 {
+        const EARTH_RADIUS_MILES: f64 = 3958.8;
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.lon - self.lon).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_MILES * a.sqrt().asin()
+    }
+}
+
+impl FromStr for Coordinate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (lat, lon) = s
+            .split_once(',')
+            .ok_or_else(|| format!("expected `LAT,LON`, found {:?}", s))?;
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<f64>()
+                .map_err(|e| format!("invalid coordinate {:?}: {}", v, e))
+        };
+        let ret = Self {
+            lat: parse(lat)?,
+            lon: parse(lon)?,
+        };
+        if !(-90.0..=90.0).contains(&ret.lat) || !(-180.0..=180.0).contains(&ret.lon) {
+            return Err(format!("{:?} is not a valid latitude and longitude", s));
+        }
+        Ok(ret)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Properties {
+    pub id: u64,
+    pub url: Option<String>,
+    pub city: Option<String>,
+    pub state: Option<String>,
+    pub address: Option<String>,
+    pub name: Option<String>,
+    pub provider: Option<String>,
+    pub postal_code: Option<String>,
+    /// The provider's own store number for this location
+    pub provider_location_id: Option<String>,
+    #[allow(dead_code)]
+    pub carries_vaccine: Option<bool>,
+    pub appointments_available: Option<bool>,
+    pub appointments_available_all_doses: Option<bool>,
+    pub appointments_available_2nd_dose_only: Option<bool>,
+    pub appointments: Option<Vec<Appointment>>,
+    /// The location's timezone, e.g. `America/New_York`
+    pub time_zone: Option<String>,
+    /// How far the location is from the closest `--near`, only filled in
+    /// for `--include-distance-in-alerts`
+    #[serde(skip)]
+    pub miles_away: Option<f64>,
+}
+
+impl Properties {
+    /// The location's own timezone, if the api sent one we know
+    pub fn timezone(&self) -> Option<Tz> {
+        self.time_zone.as_deref()?.parse().ok()
+    }
+
+    /// `time` on the location's clock, or ours if its timezone isn't known,
+    /// along with the abbreviation of the timezone used
+    pub fn local_time(&self, time: DateTime<Local>) -> (NaiveDateTime, String) {
+        match self.timezone() {
+            Some(tz) => {
+                let time = time.with_timezone(&tz);
+                (time.naive_local(), time.format("%Z").to_string())
+            }
+            None => (time.naive_local(), time.format("%Z").to_string()),
+        }
+    }
+}
+
+/// A single slot, two slots at the same time are only the same slot if
+/// they are for the same vaccine and doses
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Appointment {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub time: DateTime<Local>,
+    /// The vaccine this appointment is for, e.g. `Pfizer`
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vaccine_types: Vec<String>,
+    /// The doses this appointment is for, e.g. `all_doses` or `2nd_dose_only`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub appointment_types: Vec<String>,
+}
+
+impl Appointment {
+    /// If either of the vaccine fields mention `vaccine`, ignoring case
+    pub fn is_for_vaccine(&self, vaccine: &str) -> bool {
+        let vaccine = vaccine.to_lowercase();
+        self.kind
+            .iter()
+            .chain(&self.vaccine_types)
+            .any(|v| v.to_lowercase().contains(&vaccine))
+    }
+}
+
+/// Parse a response, reading an appointment time without an offset in its
+/// location's timezone, or in `assumed` for a location without one
+pub fn parse_response(body: &str, assumed: Option<Tz>) -> serde_json::Result<Response> {
+    use serde_json::Value;
+    let mut res: Value = serde_json::from_str(body)?;
+    let features = res
+        .get_mut("features")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten();
+    for props in features.filter_map(|f| f.get_mut("properties")) {
+        let tz = match props
+            .get("time_zone")
+            .and_then(Value::as_str)
+            .and_then(|tz| tz.parse().ok())
+            .or(assumed)
+        {
+            Some(tz) => tz,
+            None => continue,
+        };
+        let appointments = props
+            .get_mut("appointments")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten();
+        for time in appointments.filter_map(|appt| appt.get_mut("time")) {
+            if let Some(s) = time.as_str() {
+                let parsed = parse_time(s, Some(tz)).map_err(serde::de::Error::custom)?;
+                *time = Value::String(parsed.to_rfc3339());
+            }
+        }
+    }
+    serde_json::from_value(res)
+}
+
+/// Appointment times normally include an offset, and `parse_response` fills
+/// one in where it knows the timezone, anything else is read on the local clock
+fn deserialize_time<'de, D>(deserializer: D) -> Result<DateTime<Local>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_time(&s, None).map_err(serde::de::Error::custom)
+}
+
+/// Read `s` in its own offset if it has one, otherwise in `assumed` or
+/// the local timezone
+fn parse_time(s: &str, assumed: Option<Tz>) -> Result<DateTime<Local>, String> {
+    if let Ok(time) = s.parse::<DateTime<FixedOffset>>() {
+        return Ok(time.with_timezone(&Local));
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+        .map_err(|e| format!("invalid appointment time {:?}: {}", s, e))?;
+    let time = match assumed {
+        Some(tz) => tz
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|t| t.with_timezone(&Local)),
+        None => Local.from_local_datetime(&naive).earliest(),
+    };
+    time.ok_or_else(|| format!("{} doesn't exist in the assumed timezone", s))
+}
+
+impl PartialEq<DateTime<Local>> for Appointment {
+    fn eq(&self, other: &DateTime<Local>) -> bool {
+        self.time == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::America::{Chicago, New_York};
+
+    use super::*;
+
+    /// Part of a saved `states/NJ.json`
+    fn sample() -> Response {
+        parse_response(include_str!("../tests/fixtures/NJ.json"), None).unwrap()
+    }
+
+    fn at(s: &str) -> DateTime<Local> {
+        DateTime::parse_from_rfc3339(s)
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    #[test]
+    fn time_with_offset_ignores_assumed_timezone() {
+        let time = parse_time("2021-05-01T09:30:00.000-07:00", Some(New_York));
+        assert_eq!(time, Ok(at("2021-05-01T09:30:00-07:00")));
+    }
+
+    #[test]
+    fn naive_time_is_read_in_assumed_timezone() {
+        let time = parse_time("2021-05-01T09:30:00", Some(New_York));
+        assert_eq!(time, Ok(at("2021-05-01T09:30:00-04:00")));
+        let time = parse_time("2021-05-01T09:30:00.000", Some(Chicago));
+        assert_eq!(time, Ok(at("2021-05-01T09:30:00-05:00")));
+    }
+
+    #[test]
+    fn naive_time_in_dst_gap_is_an_error() {
+        // clocks in New York jumped from 2am to 3am that night
+        let time = parse_time("2021-03-14T02:30:00", Some(New_York));
+        assert!(time.unwrap_err().contains("doesn't exist"));
+    }
+
+    #[test]
+    fn unparsable_time_is_an_error() {
+        assert!(parse_time("tomorrow", Some(New_York)).is_err());
+    }
+
+    #[test]
+    fn sample_location_parses() {
+        let res = sample();
+        assert_eq!(res.features.len(), 3);
+        let cvs = &res.features[0];
+        assert_eq!(
+            cvs.geometry.as_ref().unwrap().coordinate(),
+            Coordinate {
+                lat: 40.22068,
+                lon: -74.759717
+            }
+        );
+        let props = &cvs.properties;
+        assert_eq!(props.id, 7085434);
+        assert!(props.name.is_none());
+        assert_eq!(props.provider.as_deref(), Some("cvs"));
+        assert_eq!(props.postal_code.as_deref(), Some("08619"));
+        assert_eq!(props.provider_location_id.as_deref(), Some("2301"));
+        assert_eq!(props.time_zone.as_deref(), Some("America/New_York"));
+        assert_eq!(props.timezone(), Some(New_York));
+        assert_eq!(props.appointments_available_all_doses, Some(true));
+        assert_eq!(props.appointments_available_2nd_dose_only, Some(true));
+    }
+
+    #[test]
+    fn sample_appointments_parse() {
+        let res = sample();
+        let appointments = res.features[0].properties.appointments.as_ref().unwrap();
+        assert_eq!(appointments.len(), 2);
+        assert_eq!(appointments[0].time, at("2021-05-01T09:30:00-04:00"));
+        assert_eq!(appointments[0].kind.as_deref(), Some("Pfizer"));
+        assert_eq!(appointments[0].vaccine_types, ["pfizer"]);
+        assert_eq!(appointments[0].appointment_types, ["all_doses"]);
+        assert_eq!(appointments[1].kind.as_deref(), Some("Moderna"));
+        assert_eq!(appointments[1].appointment_types, ["2nd_dose_only"]);
+    }
+
+    #[test]
+    fn sample_naive_time_parses() {
+        let res = sample();
+        let appointment = &res.features[1].properties.appointments.as_ref().unwrap()[0];
+        // the Rite Aid's own timezone, with nothing assumed
+        assert_eq!(appointment.time, at("2021-05-02T13:00:00-04:00"));
+        assert_eq!(appointment.kind.as_deref(), Some("Janssen"));
+        assert!(appointment.appointment_types.is_empty());
+    }
+
+    #[test]
+    fn naive_time_is_read_in_its_locations_timezone() {
+        let body = r#"{"features": [
+            {"properties": {"id": 1, "time_zone": "America/Chicago",
+                "appointments": [{"time": "2021-05-01T09:30:00"}]}},
+            {"properties": {"id": 2,
+                "appointments": [{"time": "2021-05-01T09:30:00"}]}}
+        ]}"#;
+        let res = parse_response(body, Some(New_York)).unwrap();
+        let time = |i: usize| res.features[i].properties.appointments.as_ref().unwrap()[0].time;
+        assert_eq!(time(0), at("2021-05-01T09:30:00-05:00"));
+        assert_eq!(time(1), at("2021-05-01T09:30:00-04:00"));
+    }
+
+    #[test]
+    fn status_error_shows_the_start_of_the_body() {
+        let err = |body: &str| StatusError {
+            url: "https://example.com/NJ.json".to_string(),
+            status: reqwest::StatusCode::NOT_FOUND,
+            retry_after: None,
+            body: body.to_string(),
+        };
+        assert_eq!(
+            err("<html>Not Found</html>\n").to_string(),
+            "https://example.com/NJ.json returned 404 Not Found: \"<html>Not Found</html>\""
+        );
+        assert_eq!(
+            err("").to_string(),
+            "https://example.com/NJ.json returned 404 Not Found"
+        );
+    }
+
+    #[test]
+    fn sample_location_without_appointments_parses() {
+        let res = sample();
+        let props = &res.features[2].properties;
+        assert_eq!(props.appointments.as_deref().map(<[_]>::len), Some(0));
+        assert_eq!(props.appointments_available, Some(false));
+        assert!(props.carries_vaccine.is_none());
+    }
+}